@@ -0,0 +1,137 @@
+//! Seed packets for the generation of new chains. A corpus directory contains one file per packet
+//! type, named like the type (`CONNECT`, `PUBLISH`, `RESERVED`, ...), with one hex encoded packet
+//! per line. Empty lines and lines starting with `#` are ignored.
+//! Packets which differ between the MQTT versions go into the `3.1.1` and `5` subdirectories, only
+//! the one of the fuzzed version is read. The files at the top apply to both versions.
+use crate::markov::{PacketType, Protocol};
+use color_eyre::eyre::{eyre, WrapErr};
+use color_eyre::Result;
+use rand::seq::SliceRandom;
+use rand_xoshiro::Xoshiro256PlusPlus;
+use std::collections::HashMap;
+use std::path::Path;
+use std::str::FromStr;
+use tokio::fs;
+use tracing::warn;
+
+/// The corpus shipped with the repository
+pub const DEFAULT_CORPUS: &str = "./mqtt_corpus";
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Corpus {
+    pub(crate) inner: HashMap<PacketType, Vec<Vec<u8>>>,
+}
+
+impl Corpus {
+    /// Reads the packet files of the directory and of the subdirectory of the protocol, if it has
+    /// one
+    pub async fn read_from_dir(path: impl AsRef<Path>, protocol: Protocol) -> Result<Self> {
+        let mut corpus = Self::read_packet_files(path.as_ref()).await?;
+        if let Some(version) = version_dir(protocol) {
+            let path = path.as_ref().join(version);
+            if path.is_dir() {
+                corpus.extend(Self::read_packet_files(&path).await?);
+            }
+        }
+        Ok(corpus)
+    }
+
+    /// Reads all packet files of the directory. Files which aren't named after a packet type are
+    /// skipped, as are subdirectories
+    async fn read_packet_files(path: &Path) -> Result<Self> {
+        let mut corpus = Self::default();
+        let mut entries = fs::read_dir(path)
+            .await
+            .wrap_err_with(|| format!("Could not read corpus {}", path.display()))?;
+        while let Some(entry) = entries.next_entry().await? {
+            if entry.file_type().await?.is_dir() {
+                continue;
+            }
+            let path = entry.path();
+            let Some(packet_type) = path
+                .file_name()
+                .and_then(|name| PacketType::from_str(&name.to_string_lossy()).ok())
+            else {
+                warn!(
+                    "Skipping {}, it is not named after a packet type",
+                    path.display()
+                );
+                continue;
+            };
+            let content = fs::read_to_string(&path).await?;
+            let packets = parse(&content)
+                .wrap_err_with(|| format!("Invalid corpus file {}", path.display()))?;
+            corpus.inner.entry(packet_type).or_default().extend(packets);
+        }
+        Ok(corpus)
+    }
+
+    /// Adds the packets of the other corpus
+    pub fn extend(&mut self, other: Corpus) {
+        for (packet_type, packets) in other.inner {
+            self.inner.entry(packet_type).or_default().extend(packets);
+        }
+    }
+
+    /// A random packet of the type, if the corpus has any
+    pub fn choose(&self, packet_type: &PacketType, rng: &mut Xoshiro256PlusPlus) -> Option<&[u8]> {
+        self.inner.get(packet_type)?.choose(rng).map(Vec::as_slice)
+    }
+
+    pub fn len(&self) -> usize {
+        self.inner.values().map(Vec::len).sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+/// The subdirectory with the packets which only the protocol understands
+fn version_dir(protocol: Protocol) -> Option<&'static str> {
+    match protocol {
+        Protocol::Mqtt => Some("3.1.1"),
+        Protocol::Mqtt5 => Some("5"),
+        Protocol::MqttSn => None,
+    }
+}
+
+fn parse(content: &str) -> Result<Vec<Vec<u8>>> {
+    content
+        .lines()
+        .enumerate()
+        .map(|(number, line)| (number, line.trim()))
+        .filter(|(_, line)| !line.is_empty() && !line.starts_with('#'))
+        .map(|(number, line)| hex::decode(line).map_err(|e| eyre!("Line {}: {}", number + 1, e)))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::SeedableRng;
+
+    #[test]
+    fn test_parse() {
+        let packets = parse("# PINGREQ\nc000\n\n  e000  \n").unwrap();
+        assert_eq!(packets, vec![vec![0xC0, 0], vec![0xE0, 0]]);
+        assert!(parse("c000\nxyz\n").is_err());
+    }
+
+    #[tokio::test]
+    async fn test_read_shipped_corpus() {
+        let path = concat!(env!("CARGO_MANIFEST_DIR"), "/../mqtt_corpus");
+        let mut rng = Xoshiro256PlusPlus::seed_from_u64(0);
+        for (protocol, level) in [(Protocol::Mqtt, 4), (Protocol::Mqtt5, 5)] {
+            let corpus = Corpus::read_from_dir(path, protocol).await.unwrap();
+            // Every packet type has a file
+            assert_eq!(corpus.inner.len(), 16, "{protocol:?}");
+            // Only CONNECTs of the fuzzed version are used
+            for connect in &corpus.inner[&PacketType::CONNECT] {
+                assert_eq!(connect[0], 0x10);
+                assert_eq!(connect[8], level, "{protocol:?}");
+            }
+            assert!(corpus.choose(&PacketType::CONNECT, &mut rng).is_some());
+        }
+    }
+}
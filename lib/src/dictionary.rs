@@ -0,0 +1,263 @@
+//! Tokens which are inserted into or written over packets by the dictionary mutation. The
+//! dictionary starts with MQTT specific tokens, more are read from AFL dictionaries (`-x` format)
+//! or TOML files and extracted from the corpus and from the responses of the broker.
+use crate::corpus::Corpus;
+use crate::mqtt::decode::decode;
+use crate::mqtt::MqttVersion;
+use color_eyre::eyre::{bail, eyre, WrapErr};
+use color_eyre::Result;
+use rand::seq::SliceRandom;
+use rand_xoshiro::Xoshiro256PlusPlus;
+use serde::Deserialize;
+use std::collections::HashSet;
+use std::path::Path;
+use std::sync::RwLock;
+use tokio::fs;
+
+// Tokens the broker treats specially or which are likely to trip up its UTF-8 handling
+const BUILTIN_TOKENS: [&[u8]; 21] = [
+    b"#",
+    b"+",
+    b"/",
+    b"$SYS/",
+    b"$SYS/#",
+    b"$share/",
+    b"$share/group/",
+    b"MQTT",
+    b"MQIsdp",
+    // U+0000, which is not allowed in MQTT strings
+    &[0xC0, 0x80],
+    &[0],
+    // Overlong encodings of '/'
+    &[0xC0, 0xAF],
+    &[0xE0, 0x80, 0xAF],
+    &[0xF0, 0x80, 0x80, 0xAF],
+    // 5 and 6 byte sequences from before UTF-8 was limited to 4 bytes
+    &[0xF8, 0x88, 0x80, 0x80, 0x80],
+    &[0xFC, 0x84, 0x80, 0x80, 0x80, 0x80],
+    // U+FFFF and U+10FFFF, non-characters
+    &[0xEF, 0xBF, 0xBF],
+    &[0xF4, 0x8F, 0xBF, 0xBF],
+    // A byte order mark, which must not be skipped
+    &[0xEF, 0xBB, 0xBF],
+    // UTF-16 surrogate
+    &[0xED, 0xA0, 0x80],
+    // Lone continuation byte
+    &[0x80],
+];
+// Extracted tokens are limited, so the responses of a long campaign don't flood the dictionary
+const MAX_TOKENS: usize = 4096;
+// Shorter runs of printable bytes are mostly noise
+const MIN_PRINTABLE_RUN: usize = 3;
+const MAX_TOKEN_LEN: usize = 64;
+
+#[derive(Debug, Default)]
+struct Tokens {
+    list: Vec<Vec<u8>>,
+    known: HashSet<Vec<u8>>,
+}
+
+#[derive(Debug)]
+pub struct Dictionary {
+    // Grows while fuzzing, so it is shared between the threads behind a lock
+    tokens: RwLock<Tokens>,
+}
+
+impl Default for Dictionary {
+    fn default() -> Self {
+        let dictionary = Self {
+            tokens: Default::default(),
+        };
+        for token in BUILTIN_TOKENS {
+            dictionary.add(token);
+        }
+        // Very long UTF-8 strings
+        dictionary.add(&"€".repeat(1000).into_bytes());
+        dictionary
+    }
+}
+
+/// A dictionary in TOML. Tokens which aren't valid UTF-8 are given as hex
+#[derive(Deserialize)]
+struct TomlDictionary {
+    #[serde(default)]
+    tokens: Vec<String>,
+    #[serde(default)]
+    hex: Vec<String>,
+}
+
+impl Dictionary {
+    /// Adds the tokens of the file. Files ending in .toml are read as TOML, all others in the
+    /// format of AFL dictionaries
+    pub async fn read_from_file(&self, path: impl AsRef<Path>) -> Result<()> {
+        let path = path.as_ref();
+        let content = fs::read_to_string(path)
+            .await
+            .wrap_err_with(|| format!("Could not read dictionary {}", path.display()))?;
+        let tokens = match path
+            .extension()
+            .is_some_and(|extension| extension == "toml")
+        {
+            true => {
+                let dictionary: TomlDictionary = toml::from_str(&content)?;
+                let mut tokens: Vec<Vec<u8>> = dictionary
+                    .tokens
+                    .into_iter()
+                    .map(String::into_bytes)
+                    .collect();
+                for token in dictionary.hex {
+                    tokens.push(hex::decode(token)?);
+                }
+                tokens
+            }
+            false => parse_afl(&content)
+                .wrap_err_with(|| format!("Invalid dictionary {}", path.display()))?,
+        };
+        for token in tokens {
+            self.add(&token);
+        }
+        Ok(())
+    }
+
+    /// Adds the strings of the corpus packets, like topics, client ids and protocol names
+    pub fn extract_from_corpus(&self, corpus: &Corpus, version: MqttVersion) {
+        for packet in corpus.inner.values().flatten() {
+            self.extract_from_packet(packet, version);
+        }
+    }
+
+    /// Adds the strings of the packet and runs of printable bytes, which finds strings the
+    /// decoder doesn't know about, like reason strings of MQTT 5
+    pub fn extract_from_packet(&self, packet: &[u8], version: MqttVersion) {
+        let strings = decode(packet, version)
+            .map(|layout| layout.strings)
+            .unwrap_or_default();
+        let runs = packet
+            .split(|byte| !byte.is_ascii_graphic() && *byte != b' ')
+            .filter(|run| run.len() >= MIN_PRINTABLE_RUN);
+        for token in strings.into_iter().map(|field| &packet[field]).chain(runs) {
+            // Long payloads would only bloat the packets
+            if token.len() <= MAX_TOKEN_LEN {
+                self.add(token);
+            }
+        }
+    }
+
+    /// Adds the token, unless it is empty, known or the dictionary is full
+    pub fn add(&self, token: &[u8]) {
+        if token.is_empty() {
+            return;
+        }
+        let mut tokens = self.tokens.write().unwrap();
+        if tokens.list.len() >= MAX_TOKENS || tokens.known.contains(token) {
+            return;
+        }
+        tokens.known.insert(token.to_vec());
+        tokens.list.push(token.to_vec());
+    }
+
+    pub fn choose(&self, rng: &mut Xoshiro256PlusPlus) -> Option<Vec<u8>> {
+        self.tokens.read().unwrap().list.choose(rng).cloned()
+    }
+
+    pub fn len(&self) -> usize {
+        self.tokens.read().unwrap().list.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+/// Parses a dictionary in the AFL format: one `name="token"` or `"token"` per line, with `\\`,
+/// `\"` and `\xNN` escapes
+fn parse_afl(content: &str) -> Result<Vec<Vec<u8>>> {
+    let mut tokens = Vec::new();
+    for (number, line) in content.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let (Some(start), Some(end)) = (line.find('"'), line.rfind('"')) else {
+            bail!("Line {}: the token has to be in quotes", number + 1);
+        };
+        if start == end {
+            bail!("Line {}: the token has to be in quotes", number + 1);
+        }
+        let token =
+            unescape(&line[start + 1..end]).map_err(|e| eyre!("Line {}: {e}", number + 1))?;
+        tokens.push(token);
+    }
+    Ok(tokens)
+}
+
+fn unescape(token: &str) -> Result<Vec<u8>> {
+    let mut bytes = Vec::with_capacity(token.len());
+    let mut chars = token.bytes();
+    while let Some(byte) = chars.next() {
+        if byte != b'\\' {
+            bytes.push(byte);
+            continue;
+        }
+        match chars.next() {
+            Some(b'\\') => bytes.push(b'\\'),
+            Some(b'"') => bytes.push(b'"'),
+            Some(b'x') => {
+                let hex = [chars.next(), chars.next()];
+                let [Some(high), Some(low)] = hex else {
+                    bail!("Incomplete \\x escape");
+                };
+                bytes.push(hex::decode([high, low])?[0]);
+            }
+            _ => bail!("Unknown escape sequence"),
+        }
+    }
+    Ok(bytes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_afl() {
+        let tokens = parse_afl(
+            r##"
+            # MQTT tokens
+            wildcard="#"
+            "$SYS/"
+            overlong@1="\xc0\xaf"
+            quote="\"\\"
+            "##,
+        )
+        .unwrap();
+        assert_eq!(
+            tokens,
+            vec![
+                b"#".to_vec(),
+                b"$SYS/".to_vec(),
+                vec![0xC0, 0xAF],
+                b"\"\\".to_vec()
+            ]
+        );
+        assert!(parse_afl("token=\"\\x4\"").is_err());
+        assert!(parse_afl("token").is_err());
+    }
+
+    #[test]
+    fn test_extract_tokens() {
+        let dictionary = Dictionary::default();
+        let builtin = dictionary.len();
+        // Subscription to "topic" with packet identifier 100
+        let subscribe = [130, 10, 0, 100, 0, 5, b't', b'o', b'p', b'i', b'c', 0];
+        dictionary.extract_from_packet(&subscribe, MqttVersion::V311);
+        dictionary.extract_from_packet(&subscribe, MqttVersion::V311);
+        assert_eq!(dictionary.len(), builtin + 1);
+        assert!(dictionary
+            .tokens
+            .read()
+            .unwrap()
+            .known
+            .contains(b"topic".as_slice()));
+    }
+}
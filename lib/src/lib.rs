@@ -3,7 +3,8 @@
 //! and uses markov chains to generate new packet chains. If it discovers a new response behaviour the chain is added to the fuzzing queue.
 //! We use [tokio](https://tokio.rs/) for async networking.
 //! ## The state machine
-//! We implement a State machine with a markov chain. All probabilities are configurable for this process(except the ones with only one option).
+//! We implement a State machine with a markov chain. All probabilities are configurable for this process(except the ones with only one option)
+//! through a [MarkovConfig](markov::config::MarkovConfig).
 //! The state machine is defined as follows for the Mutation Guided Fuzzing:
 //! - S0: Initial State: Either goto CONNECT state or select a packet from the queue and go to MUTATION state
 //! - CONNECT: Add connect to the current chain and go to ADDING State
@@ -11,22 +12,22 @@
 //! - MUTATION: Mutate, delete, inject or SEND the current chain
 //! - SEND: Send the current chain and either go to Sf or MUTATION state
 //! - Sf: Final State
+//!
 //! And this way for Generation Guided Fuzzing:
 //! - S0: Initial State: Goto ADD(CONNECT) state
 //! - CONNECT: Add connect to the current chain and go to S1
 //! - S1: Either add a new packet or go to S2
 //! - S2: Inject/Delete/Mutate the current chain or go to SEND
 //! - SEND: Send the current chain and either go to Sf or S2
+//!
 //! Once they get to S2 they behave the same way.
-use crate::markov::MAX_PACKETS;
-use crate::packets::PacketQueue;
-use clap::{Parser, Subcommand};
-use rand::Rng;
 use serde::{Deserialize, Serialize};
-use std::str::FromStr;
 
+pub mod corpus;
+pub mod dictionary;
 pub mod markov;
 pub mod mqtt;
+pub mod mqtt_sn;
 pub mod network;
 mod packet_pool;
 pub mod packets;
@@ -45,8 +46,7 @@ pub struct SeedAndIterations {
 
 #[cfg(test)]
 mod tests {
-    use super::*;
-    use crate::packets::Packets;
+    use crate::packets::{PacketQueue, Packets};
     #[test]
     fn test_serialize_packet_queue() {
         let mut packet_queue = PacketQueue::default();
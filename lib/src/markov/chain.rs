@@ -0,0 +1,191 @@
+//! Mutations of the sequence of packets instead of their bytes. Stateful bugs of the broker often
+//! need a packet at an unexpected point of the session, a packet repeated many times or a session
+//! without a CONNECT.
+use crate::markov::Protocol;
+use crate::mqtt::decode::CONNECT;
+use crate::mqtt_sn;
+use crate::packets::{PacketQueue, Packets};
+use rand::seq::IteratorRandom;
+use rand::Rng;
+use rand_xoshiro::Xoshiro256PlusPlus;
+
+/// Swaps two packets of the chain
+pub fn reorder(packets: &mut Packets, rng: &mut Xoshiro256PlusPlus) {
+    if packets.len() < 2 {
+        return;
+    }
+    let first = rng.gen_range(0..packets.len());
+    // Any other packet, swapping a packet with itself changes nothing
+    let second = (first + rng.gen_range(1..packets.len())) % packets.len();
+    packets.inner.swap(first, second);
+}
+
+/// Repeats a packet of the chain up to `max_copies` times, right after the original
+pub fn duplicate(packets: &mut Packets, rng: &mut Xoshiro256PlusPlus, max_copies: usize) {
+    if packets.is_empty() || max_copies == 0 {
+        return;
+    }
+    let index = rng.gen_range(0..packets.len());
+    let copies = rng.gen_range(1..=max_copies);
+    let packet = packets.inner[index].clone();
+    packets
+        .inner
+        .splice(index + 1..index + 1, std::iter::repeat_n(packet, copies));
+}
+
+/// Removes a packet from the chain, half of the time the CONNECT if there is one. A chain of one
+/// packet is left alone
+pub fn remove(packets: &mut Packets, rng: &mut Xoshiro256PlusPlus, protocol: Protocol) {
+    if packets.len() < 2 {
+        return;
+    }
+    let connect = packets.inner.iter().position(|packet| match protocol {
+        // MQTT-SN packets start with their length, the type follows it
+        Protocol::MqttSn => mqtt_sn::message_type(packet) == Some(mqtt_sn::CONNECT),
+        Protocol::Mqtt | Protocol::Mqtt5 => packet.first().is_some_and(|byte| byte >> 4 == CONNECT),
+    });
+    let index = match connect {
+        Some(index) if rng.gen_bool(0.5) => index,
+        _ => rng.gen_range(0..packets.len()),
+    };
+    packets.inner.remove(index);
+}
+
+/// Joins the head of the chain with the tail of a chain from the queue
+pub fn splice(
+    packets: &mut Packets,
+    packet_queue: &PacketQueue,
+    rng: &mut Xoshiro256PlusPlus,
+    max_packets: usize,
+) {
+    let Some(other) = packet_queue.inner.values().choose(rng) else {
+        return;
+    };
+    *packets = cross(packets, other, rng, max_packets);
+}
+
+/// Replaces the chain by the head of one chain from the queue and the tail of another
+pub fn crossover(
+    packets: &mut Packets,
+    packet_queue: &PacketQueue,
+    rng: &mut Xoshiro256PlusPlus,
+    max_packets: usize,
+) {
+    let (Some(first), Some(second)) = (
+        packet_queue.inner.values().choose(rng),
+        packet_queue.inner.values().choose(rng),
+    ) else {
+        return;
+    };
+    *packets = cross(first, second, rng, max_packets);
+}
+
+fn cross(
+    head: &Packets,
+    tail: &Packets,
+    rng: &mut Xoshiro256PlusPlus,
+    max_packets: usize,
+) -> Packets {
+    let head_end = rng.gen_range(0..=head.len());
+    let tail_start = rng.gen_range(0..=tail.len());
+    let mut chain = Packets::new();
+    for packet in head.inner[..head_end]
+        .iter()
+        .chain(&tail.inner[tail_start..])
+    {
+        chain.append(packet);
+    }
+    chain.inner.truncate(max_packets);
+    // An empty chain would have nothing left to mutate or send
+    if chain.is_empty() {
+        return head.clone();
+    }
+    chain
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::SeedableRng;
+
+    fn chain(first_bytes: &[u8]) -> Packets {
+        let mut packets = Packets::new();
+        for byte in first_bytes {
+            packets.append(&[*byte, 0]);
+        }
+        packets
+    }
+
+    #[test]
+    fn test_reorder_duplicate_remove() {
+        let mut rng = Xoshiro256PlusPlus::seed_from_u64(0);
+        for _ in 0..100 {
+            let mut packets = chain(&[0x10, 0x82, 0xE0]);
+            reorder(&mut packets, &mut rng);
+            // Two packets always change places
+            assert_ne!(packets, chain(&[0x10, 0x82, 0xE0]));
+            let mut sorted = packets.inner.clone();
+            sorted.sort();
+            assert_eq!(sorted, chain(&[0x10, 0x82, 0xE0]).inner);
+
+            let mut packets = chain(&[0x10, 0x82, 0xE0]);
+            duplicate(&mut packets, &mut rng, 50);
+            assert!((4..=53).contains(&packets.len()));
+            assert_eq!(packets.inner.last().unwrap(), &vec![0xE0, 0]);
+
+            let mut packets = chain(&[0x10, 0x82]);
+            remove(&mut packets, &mut rng, Protocol::Mqtt);
+            assert_eq!(packets.len(), 1);
+            remove(&mut packets, &mut rng, Protocol::Mqtt);
+            assert_eq!(packets.len(), 1);
+        }
+    }
+
+    #[test]
+    fn test_remove_sn_connect() {
+        let mut rng = Xoshiro256PlusPlus::seed_from_u64(0);
+        let connect = mqtt_sn::generate_connect_packet().to_vec();
+        // A PUBLISH of 16 bytes, which looks like an MQTT CONNECT
+        let publish = vec![
+            16, 12, 2, b't', b'o', 0, 0, b'h', b'e', b'l', b'l', b'o', 0, 0, 0, 0,
+        ];
+        let mut removed_connect = 0;
+        for _ in 0..1000 {
+            let mut packets = Packets::new();
+            packets.append(&publish);
+            packets.append(&connect);
+            packets.append(&[2, 0x18]);
+            remove(&mut packets, &mut rng, Protocol::MqttSn);
+            assert_eq!(packets.len(), 2);
+            if !packets.inner.contains(&connect) {
+                removed_connect += 1;
+            }
+        }
+        // Half of the time the CONNECT, otherwise any of the three packets
+        assert!((600..730).contains(&removed_connect), "{removed_connect}");
+    }
+
+    #[test]
+    fn test_crossover() {
+        let mut rng = Xoshiro256PlusPlus::seed_from_u64(0);
+        let mut packet_queue = PacketQueue::default();
+        packet_queue
+            .inner
+            .insert(vec![1], chain(&[0x10, 0x30, 0x30]));
+        packet_queue
+            .inner
+            .insert(vec![2], chain(&[0x10, 0x82, 0x82]));
+        for _ in 0..100 {
+            let mut packets = chain(&[0x10, 0xE0]);
+            splice(&mut packets, &packet_queue, &mut rng, 4);
+            assert!((1..=4).contains(&packets.len()));
+            crossover(&mut packets, &packet_queue, &mut rng, 4);
+            // Only packets of the queue are left
+            assert!(packets.inner.iter().all(|packet| packet[0] != 0xE0));
+        }
+        // Without a queue there is nothing to cross with
+        let mut packets = chain(&[0x10]);
+        crossover(&mut packets, &PacketQueue::default(), &mut rng, 4);
+        assert_eq!(packets, chain(&[0x10]));
+    }
+}
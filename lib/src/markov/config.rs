@@ -0,0 +1,352 @@
+//! The probabilities of the state machine. Every transition probability and the weights of the
+//! modes, packet types and mutations can be set in a TOML file, missing values keep their defaults.
+//! Weights are relative to the other weights of the same table, a weight of 0 disables the entry
+//! and entries missing from a table are never chosen.
+use crate::markov::mutations::{InjectType, LengthFix};
+use crate::markov::{Mode, MutationKind, Mutations, PacketType, Protocol, MAX_PACKETS};
+use color_eyre::eyre::{bail, WrapErr};
+use color_eyre::Result;
+use rand::Rng;
+use rand_xoshiro::Xoshiro256PlusPlus;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::path::Path;
+use tokio::fs;
+
+// Leaves room for the adaptive scheduler to boost the weights without overflowing
+const MAX_TOTAL_WEIGHT: f32 = 1e30;
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct MarkovConfig {
+    /// Chance that a mutation guided chain starts with a new CONNECT instead of a chain from the
+    /// queue
+    pub sel_from_queue: f32,
+    /// Chance to add another packet to the chain before mutating it
+    pub packet_append_chance: f32,
+    /// Chance to send the chain instead of mutating it
+    pub send_chance: f32,
+    /// Chance to mutate the chain again after it has been sent
+    pub mut_after_send: f32,
+    /// Chance of a TLS mutation, if the stream allows them
+    pub tls_mutation_chance: f32,
+    /// Chance of a property mutation when fuzzing MQTT 5
+    pub property_mutation_chance: f32,
+    /// Chance to take a new packet from the corpus instead of generating it
+    pub corpus_chance: f32,
+    /// Chance to split, coalesce, trickle or cut off the packets when sending the chain
+    pub wire_mutation_chance: f32,
+    /// The longest delay between two pieces of a split or trickled packet, in milliseconds
+    pub max_wire_delay: u64,
+    /// How long a trickled packet stalls in the middle, in milliseconds. Brokers only disconnect
+    /// the client if this is longer than the keep alive of the CONNECT
+    pub stall_time: u64,
+    /// No more packets are added to chains of this length
+    pub max_packets: usize,
+    /// The most copies of a packet the duplicate mutation adds. Chains can grow beyond
+    /// `max_packets` this way
+    pub max_duplicates: usize,
+    pub modes: BTreeMap<Mode, f32>,
+    /// The packet types which are added to the chain
+    pub packet_types: BTreeMap<PacketType, f32>,
+    pub mutations: BTreeMap<MutationKind, f32>,
+    pub inject_types: BTreeMap<InjectType, f32>,
+    pub length_fixes: BTreeMap<LengthFix, f32>,
+}
+
+impl Default for MarkovConfig {
+    fn default() -> Self {
+        Self {
+            sel_from_queue: 0.7,
+            packet_append_chance: 0.2,
+            send_chance: 0.2,
+            mut_after_send: 0.7,
+            tls_mutation_chance: 0.1,
+            property_mutation_chance: 0.3,
+            corpus_chance: 0.5,
+            wire_mutation_chance: 0.05,
+            max_wire_delay: 20,
+            stall_time: 1500,
+            max_packets: MAX_PACKETS,
+            max_duplicates: 50,
+            modes: BTreeMap::from([(Mode::MutationGuided, 1.0), (Mode::GenerationGuided, 1.0)]),
+            packet_types: BTreeMap::from([
+                (PacketType::AUTH, 1.0),
+                (PacketType::CONNACK, 1.0),
+                (PacketType::CONNECT, 1.0),
+                (PacketType::DISCONNECT, 1.0),
+                (PacketType::PINGREQ, 1.0),
+                (PacketType::PINGRESP, 1.0),
+                (PacketType::PUBACK, 1.0),
+                (PacketType::PUBCOMP, 1.0),
+                (PacketType::PUBLISH, 1.0),
+                (PacketType::PUBREC, 1.0),
+                (PacketType::PUBREL, 1.0),
+                (PacketType::RESERVED, 1.0),
+                (PacketType::SUBACK, 1.0),
+                (PacketType::SUBSCRIBE, 1.0),
+                (PacketType::UNSUBACK, 1.0),
+                (PacketType::UNSUBSCRIBE, 1.0),
+            ]),
+            mutations: BTreeMap::from([
+                (MutationKind::Inject, 1.0),
+                (MutationKind::Delete, 1.0),
+                (MutationKind::Swap, 1.0),
+                (MutationKind::BitFlip, 1.0),
+                (MutationKind::NibbleFlip, 1.0),
+                (MutationKind::Arithmetic, 1.0),
+                (MutationKind::InterestingValue, 1.0),
+                (MutationKind::BlockDuplicate, 1.0),
+                (MutationKind::BlockOverwrite, 1.0),
+                (MutationKind::BlockDelete, 1.0),
+                (MutationKind::Havoc, 1.0),
+                (MutationKind::Dictionary, 1.0),
+                (MutationKind::Topic, 1.0),
+                (MutationKind::ClientId, 1.0),
+                (MutationKind::QoS, 1.0),
+                (MutationKind::ReservedFlags, 1.0),
+                (MutationKind::PacketId, 1.0),
+                (MutationKind::WillFlags, 1.0),
+                (MutationKind::Reorder, 1.0),
+                (MutationKind::Duplicate, 1.0),
+                (MutationKind::RemovePacket, 1.0),
+                (MutationKind::Splice, 1.0),
+                (MutationKind::Crossover, 1.0),
+            ]),
+            inject_types: BTreeMap::from([(InjectType::Single, 1.0), (InjectType::BOF, 1.0)]),
+            length_fixes: BTreeMap::from([
+                (LengthFix::Keep, 0.4),
+                (LengthFix::RemainingLength, 0.3),
+                (LengthFix::All, 0.3),
+            ]),
+        }
+    }
+}
+
+impl MarkovConfig {
+    pub async fn read_from_file(path: impl AsRef<Path>) -> Result<Self> {
+        let content = fs::read_to_string(path.as_ref())
+            .await
+            .wrap_err_with(|| format!("Could not read {}", path.as_ref().display()))?;
+        let config: Self = toml::from_str(&content)?;
+        config.validate()?;
+        Ok(config)
+    }
+
+    /// Checks that the probabilities are between 0 and 1 and that every table has finite, non-negative
+    /// weights with a positive total
+    pub fn validate(&self) -> Result<()> {
+        for (name, chance) in [
+            ("sel_from_queue", self.sel_from_queue),
+            ("packet_append_chance", self.packet_append_chance),
+            ("send_chance", self.send_chance),
+            ("mut_after_send", self.mut_after_send),
+            ("tls_mutation_chance", self.tls_mutation_chance),
+            ("property_mutation_chance", self.property_mutation_chance),
+            ("corpus_chance", self.corpus_chance),
+            ("wire_mutation_chance", self.wire_mutation_chance),
+        ] {
+            if !(0.0..=1.0).contains(&chance) {
+                bail!("{name} has to be between 0 and 1, not {chance}");
+            }
+        }
+        if self.max_packets == 0 {
+            bail!("max_packets has to be at least 1");
+        }
+        for (name, weights) in [
+            ("modes", weights_of(&self.modes)),
+            ("packet_types", weights_of(&self.packet_types)),
+            ("mutations", weights_of(&self.mutations)),
+            ("inject_types", weights_of(&self.inject_types)),
+            ("length_fixes", weights_of(&self.length_fixes)),
+        ] {
+            if let Some(weight) = weights
+                .iter()
+                .find(|weight| !weight.is_finite() || **weight < 0.0)
+            {
+                bail!("The weights of {name} have to be finite and at least 0, not {weight}");
+            }
+            let total: f32 = weights.iter().sum();
+            if !total.is_finite() || total > MAX_TOTAL_WEIGHT {
+                bail!("The weights of {name} have to add up to at most {MAX_TOTAL_WEIGHT}");
+            }
+            if total <= 0.0 {
+                bail!("The weights of {name} have to add up to more than 0");
+            }
+        }
+        Ok(())
+    }
+
+    /// MQTT-SN packets are only mutated with byte and chain mutations, so one of them needs a weight
+    pub fn validate_for_protocol(&self, protocol: Protocol) -> Result<()> {
+        if protocol == Protocol::MqttSn && total(&self.byte_level_mutations()) <= 0.0 {
+            bail!("MQTT-SN needs a weight for at least one byte or chain mutation");
+        }
+        Ok(())
+    }
+
+    /// The mutations which don't need to understand the packets
+    fn byte_level_mutations(&self) -> BTreeMap<MutationKind, f32> {
+        self.mutations
+            .iter()
+            .filter(|(kind, _)| {
+                !matches!(
+                    kind,
+                    MutationKind::Topic
+                        | MutationKind::ClientId
+                        | MutationKind::QoS
+                        | MutationKind::ReservedFlags
+                        | MutationKind::PacketId
+                        | MutationKind::WillFlags
+                )
+            })
+            .map(|(kind, weight)| (*kind, *weight))
+            .collect()
+    }
+
+    pub fn sample_mode(&self, rng: &mut Xoshiro256PlusPlus) -> Mode {
+        weighted(&self.modes, rng).unwrap_or(Mode::GenerationGuided)
+    }
+
+    pub fn sample_packet_type(&self, rng: &mut Xoshiro256PlusPlus) -> PacketType {
+        weighted(&self.packet_types, rng).unwrap_or(PacketType::CONNECT)
+    }
+
+    /// Samples a mutation. With `byte_level` only byte and chain mutations are sampled and length
+    /// fields are never repaired, for packets which aren't MQTT
+    pub fn sample_mutation(&self, rng: &mut Xoshiro256PlusPlus, byte_level: bool) -> Mutations {
+        let kind = match byte_level {
+            true => weighted(&self.byte_level_mutations(), rng),
+            false => weighted(&self.mutations, rng),
+        };
+        let length_fix = match byte_level {
+            true => LengthFix::Keep,
+            false => self.sample_length_fix(rng),
+        };
+        // Configurations without a mutation to sample are rejected when they are validated
+        let Some(kind) = kind else {
+            unreachable!("No mutation has a weight, the configuration wasn't validated")
+        };
+        match kind {
+            MutationKind::Inject => Mutations::Inject(
+                weighted(&self.inject_types, rng).unwrap_or(InjectType::Single),
+                length_fix,
+            ),
+            MutationKind::Delete => Mutations::Delete(length_fix),
+            MutationKind::Swap => Mutations::Swap(length_fix),
+            MutationKind::BitFlip => Mutations::BitFlip(length_fix),
+            MutationKind::NibbleFlip => Mutations::NibbleFlip(length_fix),
+            MutationKind::Arithmetic => Mutations::Arithmetic(length_fix),
+            MutationKind::InterestingValue => Mutations::InterestingValue(length_fix),
+            MutationKind::BlockDuplicate => Mutations::BlockDuplicate(length_fix),
+            MutationKind::BlockOverwrite => Mutations::BlockOverwrite(length_fix),
+            MutationKind::BlockDelete => Mutations::BlockDelete(length_fix),
+            MutationKind::Havoc => Mutations::Havoc(length_fix),
+            MutationKind::Dictionary => Mutations::Dictionary(length_fix),
+            MutationKind::Topic => Mutations::Topic,
+            MutationKind::ClientId => Mutations::ClientId,
+            MutationKind::QoS => Mutations::QoS,
+            MutationKind::ReservedFlags => Mutations::ReservedFlags,
+            MutationKind::PacketId => Mutations::PacketId,
+            MutationKind::WillFlags => Mutations::WillFlags,
+            MutationKind::Reorder => Mutations::Reorder,
+            MutationKind::Duplicate => Mutations::Duplicate,
+            MutationKind::RemovePacket => Mutations::RemovePacket,
+            MutationKind::Splice => Mutations::Splice,
+            MutationKind::Crossover => Mutations::Crossover,
+        }
+    }
+
+    fn sample_length_fix(&self, rng: &mut Xoshiro256PlusPlus) -> LengthFix {
+        weighted(&self.length_fixes, rng).unwrap_or(LengthFix::Keep)
+    }
+}
+
+fn weights_of<K>(weights: &BTreeMap<K, f32>) -> Vec<f32> {
+    weights.values().copied().collect()
+}
+
+fn total<K>(weights: &BTreeMap<K, f32>) -> f32 {
+    weights.values().filter(|weight| **weight > 0.0).sum()
+}
+
+/// Picks a key with a chance proportional to its weight. Negative weights count as 0
+fn weighted<K: Clone>(weights: &BTreeMap<K, f32>, rng: &mut Xoshiro256PlusPlus) -> Option<K> {
+    let total = total(weights);
+    if total.is_nan() || total <= 0.0 {
+        return None;
+    }
+    let mut roll = rng.gen_range(0f32..total);
+    let mut last = None;
+    for (key, weight) in weights.iter().filter(|(_, weight)| **weight > 0.0) {
+        if roll < *weight {
+            return Some(key.clone());
+        }
+        roll -= weight;
+        last = Some(key);
+    }
+    // Rounding errors can leave a tiny bit of the roll
+    last.cloned()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::SeedableRng;
+
+    #[test]
+    fn test_partial_config() {
+        let config: MarkovConfig = toml::from_str(
+            r#"
+            send_chance = 0.5
+            [packet_types]
+            CONNECT = 1
+            PUBLISH = 3
+            [mutations]
+            topic = 1
+            "#,
+        )
+        .unwrap();
+        config.validate().unwrap();
+        assert_eq!(config.send_chance, 0.5);
+        assert_eq!(
+            config.mut_after_send,
+            MarkovConfig::default().mut_after_send
+        );
+        let mut rng = Xoshiro256PlusPlus::seed_from_u64(0);
+        let publishes = (0..1000)
+            .filter(|_| config.sample_packet_type(&mut rng) == PacketType::PUBLISH)
+            .count();
+        assert!((700..800).contains(&publishes));
+        assert_eq!(config.sample_mutation(&mut rng, false), Mutations::Topic);
+        // There is no byte or chain mutation left to sample for MQTT-SN
+        config.validate_for_protocol(Protocol::Mqtt).unwrap();
+        assert!(config.validate_for_protocol(Protocol::MqttSn).is_err());
+    }
+
+    #[test]
+    fn test_default_config_round_trip() {
+        let config = MarkovConfig::default();
+        config.validate().unwrap();
+        let serialized = toml::to_string(&config).unwrap();
+        assert_eq!(toml::from_str::<MarkovConfig>(&serialized).unwrap(), config);
+        // The example in the repository shows the defaults
+        let example = include_str!("../../../markov_config.toml");
+        assert_eq!(toml::from_str::<MarkovConfig>(example).unwrap(), config);
+        assert!(toml::from_str::<MarkovConfig>("send_chance = 2.0")
+            .unwrap()
+            .validate()
+            .is_err());
+        for invalid in [
+            "[mutations]\nswap = 0",
+            "[mutations]\nswap = inf",
+            "[mutations]\nswap = -1.0",
+            "[mutations]\nswap = 3e38\ndelete = 3e38",
+        ] {
+            let config = toml::from_str::<MarkovConfig>(invalid).unwrap();
+            assert!(config.validate().is_err(), "{invalid}");
+        }
+        // Misspelled keys aren't ignored
+        assert!(toml::from_str::<MarkovConfig>("send_chanse = 0.5").is_err());
+    }
+}
@@ -0,0 +1,283 @@
+//! Mutations of single fields of MQTT packets. The packets are decoded first, so every mutation
+//! hits the field it is meant for while the rest of the packet stays intact.
+use crate::mqtt::decode::{chain_version, decode, Layout, CONNECT, PUBLISH, SUBSCRIBE};
+use crate::mqtt::length::replace_string;
+use crate::mqtt::v5;
+use crate::mqtt::v5::PropertyMutation;
+use crate::packets::Packets;
+use rand::seq::SliceRandom;
+use rand::Rng;
+use rand_xoshiro::Xoshiro256PlusPlus;
+
+const INVALID_UTF8: [&[u8]; 5] = [
+    // Not allowed anywhere in UTF-8
+    &[0xFF],
+    // A lead byte followed by ASCII
+    &[0xC3, 0x28],
+    // Overlong encoding of U+0000
+    &[0xC0, 0x80],
+    // UTF-16 surrogate
+    &[0xED, 0xA0, 0x80],
+    // Truncated 4 byte sequence
+    &[0xF0, 0x9F, 0x98],
+];
+
+const WILDCARD_TOPICS: [&[u8]; 8] = [
+    b"#", b"+", b"a/+/b", b"a/#", b"#/a", b"a+/b", b"a/b#", b"+/+/#",
+];
+
+// Shared by all threads, so their sessions take each other over
+const SHARED_CLIENT_ID: &[u8] = b"rusty-FUME";
+
+/// Picks a random packet of the chain whose layout matches
+fn pick<'a>(
+    packets: &'a mut Packets,
+    rng: &mut Xoshiro256PlusPlus,
+    matches: impl Fn(&Layout) -> bool,
+) -> Option<(&'a mut Vec<u8>, Layout)> {
+    let version = chain_version(&packets.inner);
+    let candidates: Vec<(usize, Layout)> = packets
+        .inner
+        .iter()
+        .enumerate()
+        .filter_map(|(index, packet)| Some((index, decode(packet, version)?)))
+        .filter(|(_, layout)| matches(layout))
+        .collect();
+    let (index, layout) = candidates.choose(rng)?.clone();
+    Some((&mut packets.inner[index], layout))
+}
+
+fn random_bytes(rng: &mut Xoshiro256PlusPlus, max_len: usize) -> Vec<u8> {
+    let mut bytes = vec![0; rng.gen_range(0..=max_len)];
+    rng.fill(&mut bytes[..]);
+    bytes
+}
+
+/// Inserts the bytes at a random position of the original content
+fn insert_into(original: &[u8], bytes: &[u8], rng: &mut Xoshiro256PlusPlus) -> Vec<u8> {
+    let idx = rng.gen_range(0..=original.len());
+    let mut content = original.to_vec();
+    content.splice(idx..idx, bytes.iter().copied());
+    content
+}
+
+/// Replaces a topic name or filter with invalid UTF-8, wildcards, U+0000 and the like
+pub fn topic(packets: &mut Packets, rng: &mut Xoshiro256PlusPlus) {
+    let Some((packet, layout)) = pick(packets, rng, |layout| !layout.topics.is_empty()) else {
+        return;
+    };
+    let field = layout.topics.choose(rng).unwrap().clone();
+    let original = &packet[field.clone()];
+    let content = match rng.gen_range(0..7) {
+        0 => insert_into(original, INVALID_UTF8.choose(rng).unwrap(), rng),
+        // Wildcards are only allowed in filters, so these are most interesting for PUBLISH
+        1 => WILDCARD_TOPICS.choose(rng).unwrap().to_vec(),
+        2 => insert_into(original, &[0], rng),
+        3 => Vec::new(),
+        4 => b"$SYS/#".to_vec(),
+        5 => b"a/".repeat(rng.gen_range(100..5000)),
+        6 => random_bytes(rng, 64),
+        _ => unreachable!(),
+    };
+    replace_string(packet, field, &content);
+}
+
+/// Replaces the client id of a CONNECT with an empty, long, invalid or shared one
+pub fn client_id(packets: &mut Packets, rng: &mut Xoshiro256PlusPlus) {
+    let Some((packet, layout)) = pick(packets, rng, |layout| layout.client_id.is_some()) else {
+        return;
+    };
+    let field = layout.client_id.unwrap();
+    let original = &packet[field.clone()];
+    let content = match rng.gen_range(0..6) {
+        0 => Vec::new(),
+        1 => b"a".repeat(rng.gen_range(24..5000)),
+        2 => insert_into(original, INVALID_UTF8.choose(rng).unwrap(), rng),
+        3 => insert_into(original, &[0], rng),
+        4 => SHARED_CLIENT_ID.to_vec(),
+        5 => random_bytes(rng, 64),
+        _ => unreachable!(),
+    };
+    replace_string(packet, field, &content);
+}
+
+/// Sets the QoS of a PUBLISH, a subscription or a will to a random value, including the invalid 3
+pub fn qos(packets: &mut Packets, rng: &mut Xoshiro256PlusPlus) {
+    let Some((packet, layout)) = pick(packets, rng, |layout| {
+        layout.packet_type == PUBLISH
+            || !layout.subscription_options.is_empty()
+            || layout.connect_flags.is_some()
+    }) else {
+        return;
+    };
+    let qos: u8 = rng.gen_range(0..4);
+    match layout.packet_type {
+        PUBLISH => packet[0] = packet[0] & !0b110 | qos << 1,
+        SUBSCRIBE => {
+            let options = *layout.subscription_options.choose(rng).unwrap();
+            packet[options] = packet[options] & !0b11 | qos;
+        }
+        _ => {
+            let flags = layout.connect_flags.unwrap();
+            packet[flags] = packet[flags] & !0b11000 | qos << 3;
+        }
+    }
+}
+
+/// Sets bits which are reserved in the fixed header, the connect flags or the subscription options
+pub fn reserved_flags(packets: &mut Packets, rng: &mut Xoshiro256PlusPlus) {
+    // The flags of PUBLISH are not reserved
+    let Some((packet, layout)) = pick(packets, rng, |layout| {
+        layout.packet_type != PUBLISH
+            || layout.connect_flags.is_some()
+            || !layout.subscription_options.is_empty()
+    }) else {
+        return;
+    };
+    match rng.gen_range(0..3) {
+        0 if layout.connect_flags.is_some() => {
+            packet[layout.connect_flags.unwrap()] |= 1;
+        }
+        1 if !layout.subscription_options.is_empty() => {
+            let options = *layout.subscription_options.choose(rng).unwrap();
+            packet[options] |= rng.gen_range(1..4) << 6;
+        }
+        _ if layout.packet_type != PUBLISH => {
+            packet[0] ^= rng.gen_range(1..16);
+        }
+        // A PUBLISH without subscription options or connect flags
+        _ => {}
+    }
+}
+
+/// Sets a packet identifier to 0, the maximum, a random value or the identifier of another packet
+/// of the chain
+pub fn packet_id(packets: &mut Packets, rng: &mut Xoshiro256PlusPlus) {
+    let version = chain_version(&packets.inner);
+    let ids: Vec<[u8; 2]> = packets
+        .inner
+        .iter()
+        .filter_map(|packet| {
+            let offset = decode(packet, version)?.packet_id?;
+            Some([packet[offset], packet[offset + 1]])
+        })
+        .collect();
+    let Some((packet, layout)) = pick(packets, rng, |layout| layout.packet_id.is_some()) else {
+        return;
+    };
+    let offset = layout.packet_id.unwrap();
+    let id = match rng.gen_range(0..4) {
+        0 => [0, 0],
+        1 => [0xFF, 0xFF],
+        2 => rng.gen(),
+        3 => *ids.choose(rng).unwrap(),
+        _ => unreachable!(),
+    };
+    packet[offset..offset + 2].copy_from_slice(&id);
+}
+
+/// Flips the will flag, will QoS and will retain without changing the payload of the CONNECT, so
+/// the flags contradict the fields which are present
+pub fn will_flags(packets: &mut Packets, rng: &mut Xoshiro256PlusPlus) {
+    let Some((packet, layout)) = pick(packets, rng, |layout| {
+        layout.packet_type == CONNECT && layout.connect_flags.is_some()
+    }) else {
+        return;
+    };
+    packet[layout.connect_flags.unwrap()] ^= rng.gen_range(1..16) << 2;
+}
+
+/// Mutates the property list of a random MQTT 5 packet of the chain
+pub fn properties(
+    packets: &mut Packets,
+    rng: &mut Xoshiro256PlusPlus,
+    mutation: &PropertyMutation,
+) {
+    let mut candidates: Vec<usize> = (0..packets.inner.len())
+        .filter(|index| !packets.inner[*index].is_empty())
+        .collect();
+    candidates.shuffle(rng);
+    for index in candidates {
+        if v5::mutate_properties(&mut packets.inner[index], mutation, rng) {
+            return;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mqtt::{
+        generate_connect_packet, generate_publish_packet, generate_subscribe_packet, MqttVersion,
+    };
+    use rand::SeedableRng;
+
+    fn chain() -> Packets {
+        // The same chain every time
+        let mut rng = Xoshiro256PlusPlus::seed_from_u64(1);
+        let mut packets = Packets::new();
+        packets.append(&generate_connect_packet(&mut rng));
+        packets.append(&generate_publish_packet(&mut rng));
+        packets.append(&generate_subscribe_packet(&mut rng));
+        packets
+    }
+
+    #[test]
+    fn test_topic() {
+        let mut rng = Xoshiro256PlusPlus::seed_from_u64(0);
+        for _ in 0..100 {
+            let mut packets = chain();
+            topic(&mut packets, &mut rng);
+            // The packets are still well formed
+            for packet in &packets.inner {
+                let layout = decode(packet, MqttVersion::V311).unwrap();
+                assert!(!layout.topics.is_empty() || layout.packet_type == CONNECT);
+            }
+        }
+    }
+
+    #[test]
+    fn test_client_id() {
+        let mut rng = Xoshiro256PlusPlus::seed_from_u64(0);
+        for _ in 0..100 {
+            let mut packets = chain();
+            client_id(&mut packets, &mut rng);
+            let connect = &packets.inner[0];
+            let layout = decode(connect, MqttVersion::V311).unwrap();
+            // The will topic and message are still found behind the client id
+            assert_eq!(layout.strings.len(), 4);
+            assert_eq!(layout.strings[3].end, connect.len());
+        }
+    }
+
+    #[test]
+    fn test_packet_id() {
+        let mut rng = Xoshiro256PlusPlus::seed_from_u64(0);
+        for _ in 0..100 {
+            let mut packets = chain();
+            packet_id(&mut packets, &mut rng);
+            // The CONNECT has no packet identifier
+            assert_eq!(packets.inner[0], chain().inner[0]);
+            for (packet, original) in packets.inner.iter().zip(chain().inner.iter()) {
+                assert_eq!(packet.len(), original.len());
+            }
+        }
+    }
+
+    #[test]
+    fn test_flags() {
+        let mut rng = Xoshiro256PlusPlus::seed_from_u64(0);
+        for _ in 0..100 {
+            let mut packets = chain();
+            will_flags(&mut packets, &mut rng);
+            assert_ne!(packets.inner[0], chain().inner[0]);
+            let mut packets = chain();
+            qos(&mut packets, &mut rng);
+            reserved_flags(&mut packets, &mut rng);
+            // Flags never change the length of a packet
+            for (packet, original) in packets.inner.iter().zip(chain().inner.iter()) {
+                assert_eq!(packet.len(), original.len());
+            }
+        }
+    }
+}
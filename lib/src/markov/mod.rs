@@ -1,45 +1,63 @@
 //! ## The state machine
-//! We implement a State machine with a markov chain. All probabilities are configurable for this process(except the ones with only one option).
+//! We implement a State machine with a markov chain. All probabilities are configurable for this process(except the ones with only one option)
+//! through a [config::MarkovConfig].
 //! The state machine is defined as follows for the Mutation Guided Fuzzing:
 //! - S0: Initial State: Either goto CONNECT state or select a packet from the queue and go to MUTATION state
 //! - CONNECT: Add connect to the current chain and go to ADDING State
 //! - ADDING: Either add a new packet(configurable probability for each one) to the chain or go to MUTATION state
 //! - MUTATION: Mutate, delete, inject or SEND the current chain
-//! - SEND: Send the current chain and either go to Sf or MUTATION state
+//! - SEND: Send the current chain, possibly split, coalesced, trickled or cut off on the wire, and either go to Sf or MUTATION state
 //! - Sf: Final State
+//!
 //! And this way for Generation Guided Fuzzing:
 //! - S0: Initial State: Goto ADD(CONNECT) state
 //! - CONNECT: Add connect to the current chain and go to S1
 //! - S1: Either add a new packet or go to S2
 //! - S2: Inject/Delete/Mutate the current chain or go to SEND
 //! - SEND: Send the current chain and either go to Sf or S2
+//!
 //! Once they get to S2 they behave the same way.
+mod chain;
+pub mod config;
+mod fields;
 mod mutations;
+pub mod scheduler;
+mod tls;
+mod wire;
 
-use crate::markov::mutations::{delete, inject, swap, InjectType};
-use crate::markov::Mode::{GenerationGuided, MutationGuided};
-use crate::mqtt::{
-    generate_connect_packet, generate_disconnect_packet, generate_pingreq_packet,
-    generate_publish_packet, generate_subscribe_packet, generate_unsubscribe_packet, send_packets,
-    SendError,
+use crate::corpus::Corpus;
+use crate::dictionary::Dictionary;
+use crate::markov::config::MarkovConfig;
+use crate::markov::mutations::{
+    arithmetic, bit_flip, block_delete, block_duplicate, block_overwrite, delete, dictionary,
+    havoc, inject, interesting_value, nibble_flip, swap, InjectType, LengthFix,
 };
+use crate::markov::scheduler::Scheduler;
+use crate::markov::tls::TlsMutation;
+use crate::markov::wire::WireMutation;
+use crate::markov::Mode::{GenerationGuided, MutationGuided};
+use crate::mqtt::v5;
+use crate::mqtt::v5::PropertyMutation;
+use crate::mqtt::{generate_packet, new_behavior, send_packets, MqttVersion, SendError};
+use crate::mqtt_sn;
+use crate::mqtt_sn::SnPacketType;
+use crate::network::TlsLayer;
 use crate::packets::{PacketQueue, Packets};
-use rand::distributions::Standard;
-use rand::prelude::Distribution;
+use crate::process_monitor::Feedback;
+use color_eyre::eyre::bail;
 use rand::Rng;
 use rand_xoshiro::Xoshiro256PlusPlus;
+use serde::{Deserialize, Serialize};
 use std::default::Default;
 use std::fmt::{Debug, Display};
+use std::str::FromStr;
 use std::sync::Arc;
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::io::{AsyncRead, AsyncWrite, DuplexStream};
+use tokio::net::TcpStream;
 use tokio::sync::RwLock;
 use tracing::*;
 
-const SEL_FROM_QUEUE: f32 = 0.7;
-const PACKET_APPEND_CHANCE: f32 = 0.2;
-const SEND_CHANCE: f32 = 0.2;
-const BOF_CHANCE: f32 = 0.2;
-const MUT_AFTER_SEND: f32 = 0.7;
+/// The default maximum number of packets in a chain
 pub const MAX_PACKETS: usize = 10;
 
 #[derive(Debug, Clone, PartialEq, Eq, Hash, Default)]
@@ -47,6 +65,7 @@ pub enum State {
     #[default]
     S0,
     ADD(PacketType),
+    AddSn(SnPacketType),
     ADDING,
     SelectFromQueue,
     MUTATION,
@@ -58,26 +77,140 @@ pub enum State {
 #[derive(Debug, Clone, PartialEq, Eq, Hash)]
 pub enum Mutations {
     // Inserts bytes into the payload
-    Inject(InjectType),
+    Inject(InjectType, LengthFix),
     // Deletes bytes from the payload
-    Delete,
+    Delete(LengthFix),
     // Changes bytes in the payload
+    Swap(LengthFix),
+    // Flips a single bit
+    BitFlip(LengthFix),
+    // Flips the upper or lower four bits of a byte
+    NibbleFlip(LengthFix),
+    // Adds or subtracts a small value from a 1, 2 or 4 byte number
+    Arithmetic(LengthFix),
+    // Overwrites bytes with 0, 0x7F, 0xFF, 0xFFFF or the largest Remaining Length
+    InterestingValue(LengthFix),
+    // Copies a block of bytes to a random position
+    BlockDuplicate(LengthFix),
+    // Overwrites a block of bytes with another block or a repeated byte
+    BlockOverwrite(LengthFix),
+    // Deletes a block of bytes
+    BlockDelete(LengthFix),
+    // Several of the byte mutations at once
+    Havoc(LengthFix),
+    // Inserts or overwrites a token of the dictionary
+    Dictionary(LengthFix),
+    // Invalid UTF-8, wildcards, U+0000 etc. in a topic name or filter
+    Topic,
+    // Empty, long, invalid or shared client ids
+    ClientId,
+    // Random QoS in a PUBLISH, SUBSCRIBE or will
+    QoS,
+    // Sets bits which must be zero
+    ReservedFlags,
+    // Packet identifiers which are 0, the maximum or used twice
+    PacketId,
+    // Will flags which contradict the CONNECT payload
+    WillFlags,
+    // Swaps two packets of the chain
+    Reorder,
+    // Repeats a packet of the chain many times
+    Duplicate,
+    // Removes a packet, preferably the CONNECT
+    RemovePacket,
+    // Joins the chain with the tail of a chain from the queue
+    Splice,
+    // Joins two chains from the queue
+    Crossover,
+    // Adds, duplicates, removes or corrupts MQTT 5 properties. Only sampled for MQTT 5
+    Property(PropertyMutation),
+    // Breaks the TLS layer below the MQTT stream. Only sampled if the stream allows it
+    Tls(TlsMutation),
+}
+
+impl Mutations {
+    /// The kind of the mutation, if it is sampled by weight
+    pub fn kind(&self) -> Option<MutationKind> {
+        Some(match self {
+            Mutations::Inject(..) => MutationKind::Inject,
+            Mutations::Delete(_) => MutationKind::Delete,
+            Mutations::Swap(_) => MutationKind::Swap,
+            Mutations::BitFlip(_) => MutationKind::BitFlip,
+            Mutations::NibbleFlip(_) => MutationKind::NibbleFlip,
+            Mutations::Arithmetic(_) => MutationKind::Arithmetic,
+            Mutations::InterestingValue(_) => MutationKind::InterestingValue,
+            Mutations::BlockDuplicate(_) => MutationKind::BlockDuplicate,
+            Mutations::BlockOverwrite(_) => MutationKind::BlockOverwrite,
+            Mutations::BlockDelete(_) => MutationKind::BlockDelete,
+            Mutations::Havoc(_) => MutationKind::Havoc,
+            Mutations::Dictionary(_) => MutationKind::Dictionary,
+            Mutations::Topic => MutationKind::Topic,
+            Mutations::ClientId => MutationKind::ClientId,
+            Mutations::QoS => MutationKind::QoS,
+            Mutations::ReservedFlags => MutationKind::ReservedFlags,
+            Mutations::PacketId => MutationKind::PacketId,
+            Mutations::WillFlags => MutationKind::WillFlags,
+            Mutations::Reorder => MutationKind::Reorder,
+            Mutations::Duplicate => MutationKind::Duplicate,
+            Mutations::RemovePacket => MutationKind::RemovePacket,
+            Mutations::Splice => MutationKind::Splice,
+            Mutations::Crossover => MutationKind::Crossover,
+            Mutations::Property(_) | Mutations::Tls(_) => return None,
+        })
+    }
+}
+
+/// The mutations which are sampled by their weight in the [MarkovConfig]. TLS and property
+/// mutations have their own chance
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum MutationKind {
+    Inject,
+    Delete,
     Swap,
+    BitFlip,
+    NibbleFlip,
+    Arithmetic,
+    InterestingValue,
+    BlockDuplicate,
+    BlockOverwrite,
+    BlockDelete,
+    Havoc,
+    Dictionary,
+    Topic,
+    ClientId,
+    #[serde(rename = "qos")]
+    QoS,
+    ReservedFlags,
+    PacketId,
+    WillFlags,
+    Reorder,
+    Duplicate,
+    RemovePacket,
+    Splice,
+    Crossover,
 }
-impl Distribution<Mutations> for Standard {
-    fn sample<R: Rng + ?Sized>(&self, rng: &mut R) -> Mutations {
-        match rng.gen_range(0..3) {
-            0 => Mutations::Inject(rng.gen()),
-            1 => Mutations::Delete,
-            2 => Mutations::Swap,
-            _ => unreachable!(),
-        }
+
+pub trait ByteStream: AsyncRead + AsyncWrite + Unpin + Debug + Send {
+    /// Raw access to the TLS layer of the stream. Only streams which fuzz TLS itself return it
+    fn tls_layer(&mut self) -> Option<&mut dyn TlsLayer> {
+        None
     }
 }
 
-pub trait ByteStream: AsyncReadExt + AsyncWriteExt + Unpin + Debug + Send {}
+impl ByteStream for TcpStream {}
+
+impl ByteStream for DuplexStream {}
+
+impl<T> ByteStream for Box<T>
+where
+    T: ByteStream + ?Sized,
+{
+    fn tls_layer(&mut self) -> Option<&mut dyn TlsLayer> {
+        (**self).tls_layer()
+    }
+}
 
-impl<T> ByteStream for T where T: AsyncReadExt + AsyncWriteExt + Unpin + Debug + Send {}
 pub struct StateMachine<B>
 where
     B: ByteStream,
@@ -91,8 +224,42 @@ where
     // The current stream, TlsStream TcpStream or WebsocketStream
     stream: B,
     timeout: u16,
+    protocol: Protocol,
+    // Seed packets which are added instead of generated ones
+    corpus: Arc<Corpus>,
+    // Tokens for the dictionary mutation, which also learns from the responses
+    dictionary: Arc<Dictionary>,
+    // Coverage and log output of the broker
+    feedback: Arc<Feedback>,
+    config: Arc<MarkovConfig>,
+    // Learns from the results of the sends, if set
+    scheduler: Option<Arc<Scheduler>>,
+    // The mutations since the last send and the packet types added to the chain, for the scheduler
+    mutations: Vec<MutationKind>,
+    packet_types: Vec<PacketType>,
+}
+
+/// The protocol the state machine generates packets for
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Copy, Default)]
+pub enum Protocol {
+    #[default]
+    Mqtt,
+    Mqtt5,
+    MqttSn,
+}
+
+impl Protocol {
+    /// The MQTT version packets are decoded as. MQTT-SN packets aren't decoded
+    pub fn mqtt_version(&self) -> MqttVersion {
+        match self {
+            Protocol::Mqtt5 => MqttVersion::V5,
+            Protocol::Mqtt | Protocol::MqttSn => MqttVersion::V311,
+        }
+    }
 }
-#[derive(Debug, Clone, PartialEq, Eq, Hash, Copy)]
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Copy, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
 pub enum Mode {
     MutationGuided,
     GenerationGuided,
@@ -107,28 +274,47 @@ impl Display for Mode {
     }
 }
 
-impl Distribution<Mode> for Standard {
-    fn sample<R: Rng + ?Sized>(&self, rng: &mut R) -> Mode {
-        match rng.gen_range(0..10) {
-            0..=4 => MutationGuided,
-            5..=9 => GenerationGuided,
-            _ => unreachable!(),
-        }
-    }
-}
 impl<B> StateMachine<B>
 where
     B: ByteStream,
 {
-    pub fn new(stream: B, timeout: u16) -> Self {
+    pub fn new(stream: B, timeout: u16, config: Arc<MarkovConfig>) -> Self {
         Self {
             stream,
             state: Default::default(),
             packets: Packets::new(),
             previous_packets: Vec::new(),
             timeout,
+            protocol: Default::default(),
+            corpus: Default::default(),
+            dictionary: Default::default(),
+            feedback: Default::default(),
+            config,
+            scheduler: None,
+            mutations: Vec::new(),
+            packet_types: Vec::new(),
         }
     }
+    pub fn with_protocol(mut self, protocol: Protocol) -> Self {
+        self.protocol = protocol;
+        self
+    }
+    pub fn with_corpus(mut self, corpus: Arc<Corpus>) -> Self {
+        self.corpus = corpus;
+        self
+    }
+    pub fn with_dictionary(mut self, dictionary: Arc<Dictionary>) -> Self {
+        self.dictionary = dictionary;
+        self
+    }
+    pub fn with_feedback(mut self, feedback: Arc<Feedback>) -> Self {
+        self.feedback = feedback;
+        self
+    }
+    pub fn with_scheduler(mut self, scheduler: Arc<Scheduler>) -> Self {
+        self.scheduler = Some(scheduler);
+        self
+    }
     pub async fn execute(
         &mut self,
         mode: Mode,
@@ -149,20 +335,22 @@ where
         match &self.state {
             State::S0 => match mode {
                 MutationGuided => {
-                    if rng.gen_range(0f32..1f32) < SEL_FROM_QUEUE && !self.packets.is_full() {
-                        self.state = State::ADD(PacketType::CONNECT);
+                    if rng.gen_range(0f32..1f32) < self.config.sel_from_queue
+                        && self.packets.len() < self.config.max_packets
+                    {
+                        self.state = self.add_connect();
                     } else {
                         self.state = State::SelectFromQueue;
                     }
                 }
                 GenerationGuided => {
-                    self.state = State::ADD(PacketType::CONNECT);
+                    self.state = self.add_connect();
                 }
             },
             State::SelectFromQueue => {
                 // Maybe we should use a priority queue in-memory here instead of storing on disk(overhead). Should be measured in the future.
                 if packet_queue.read().await.inner.is_empty() {
-                    self.state = State::ADD(PacketType::CONNECT);
+                    self.state = self.add_connect();
                 } else {
                     let packet_queue_read = packet_queue.read().await;
                     let packet_index = rng.gen_range(0..packet_queue_read.inner.len());
@@ -173,93 +361,202 @@ where
                 }
             }
             State::ADD(packet_type) => {
-                match packet_type {
-                    PacketType::CONNECT => {
-                        self.packets.append(&generate_connect_packet());
-                    }
-                    PacketType::PUBLISH => {
-                        self.packets.append(&generate_publish_packet());
-                    }
-                    PacketType::SUBSCRIBE => {
-                        self.packets.append(&generate_subscribe_packet());
-                    }
-                    PacketType::UNSUBSCRIBE => {
-                        self.packets.append(&generate_unsubscribe_packet());
-                    }
-                    PacketType::PINGREQ => {
-                        self.packets.append(&generate_pingreq_packet());
-                    }
-                    PacketType::DISCONNECT => {
-                        self.packets.append(&generate_disconnect_packet());
-                    }
-                    _ => unreachable!(),
-                }
+                let seed = match rng.gen_range(0f32..1f32) < self.config.corpus_chance {
+                    true => self.corpus.choose(packet_type, rng),
+                    false => None,
+                };
+                let packet = match (seed, self.protocol) {
+                    (Some(seed), _) => seed.to_vec(),
+                    (None, Protocol::Mqtt5) => v5::generate_packet(packet_type, rng),
+                    (None, _) => generate_packet(packet_type, rng),
+                };
+                self.packets.append(&packet);
+                self.packet_types.push(packet_type.clone());
+                self.state = State::ADDING
+            }
+            State::AddSn(packet_type) => {
+                // MQTT-SN packet types aren't sampled from the weights, so they aren't scheduled
+                self.packets.append(&mqtt_sn::generate_packet(packet_type));
                 self.state = State::ADDING
             }
             State::ADDING => {
-                if rng.gen_range(0f32..1f32) < PACKET_APPEND_CHANCE {
-                    self.state = State::ADD(rng.gen());
+                if rng.gen_range(0f32..1f32) < self.config.packet_append_chance
+                    && self.packets.len() < self.config.max_packets
+                {
+                    self.state = match self.protocol {
+                        Protocol::Mqtt | Protocol::Mqtt5 => {
+                            State::ADD(self.config.sample_packet_type(rng))
+                        }
+                        Protocol::MqttSn => State::AddSn(rng.gen()),
+                    };
                 } else {
                     self.state = State::MUTATION;
                 }
             }
             State::MUTATION => {
-                if rng.gen_range(0f32..1f32) < SEND_CHANCE {
+                if rng.gen_range(0f32..1f32) < self.config.send_chance {
                     self.state = State::SEND;
                 } else {
-                    self.state = State::Mutate(rng.gen());
+                    self.state = State::Mutate(self.sample_mutation(rng));
                 }
             }
             State::Mutate(mutation) => {
+                self.mutations.extend(mutation.kind());
                 match mutation {
-                    Mutations::Inject(t) => {
-                        inject(&mut self.packets, rng, t);
+                    Mutations::Inject(t, length_fix) => {
+                        inject(&mut self.packets, rng, t, length_fix);
+                    }
+                    Mutations::Delete(length_fix) => {
+                        delete(&mut self.packets, rng, length_fix);
+                    }
+                    Mutations::Swap(length_fix) => {
+                        swap(&mut self.packets, rng, length_fix);
+                    }
+                    Mutations::BitFlip(length_fix) => bit_flip(&mut self.packets, rng, length_fix),
+                    Mutations::NibbleFlip(length_fix) => {
+                        nibble_flip(&mut self.packets, rng, length_fix)
+                    }
+                    Mutations::Arithmetic(length_fix) => {
+                        arithmetic(&mut self.packets, rng, length_fix)
                     }
-                    Mutations::Delete => {
-                        delete(&mut self.packets, rng);
+                    Mutations::InterestingValue(length_fix) => {
+                        interesting_value(&mut self.packets, rng, length_fix)
                     }
-                    Mutations::Swap => {
-                        swap(&mut self.packets, rng);
+                    Mutations::BlockDuplicate(length_fix) => {
+                        block_duplicate(&mut self.packets, rng, length_fix)
+                    }
+                    Mutations::BlockOverwrite(length_fix) => {
+                        block_overwrite(&mut self.packets, rng, length_fix)
+                    }
+                    Mutations::BlockDelete(length_fix) => {
+                        block_delete(&mut self.packets, rng, length_fix)
+                    }
+                    Mutations::Havoc(length_fix) => havoc(&mut self.packets, rng, length_fix),
+                    Mutations::Dictionary(length_fix) => {
+                        dictionary(&mut self.packets, rng, &self.dictionary, length_fix)
+                    }
+                    Mutations::Topic => fields::topic(&mut self.packets, rng),
+                    Mutations::ClientId => fields::client_id(&mut self.packets, rng),
+                    Mutations::QoS => fields::qos(&mut self.packets, rng),
+                    Mutations::ReservedFlags => fields::reserved_flags(&mut self.packets, rng),
+                    Mutations::PacketId => fields::packet_id(&mut self.packets, rng),
+                    Mutations::WillFlags => fields::will_flags(&mut self.packets, rng),
+                    Mutations::Reorder => chain::reorder(&mut self.packets, rng),
+                    Mutations::Duplicate => {
+                        chain::duplicate(&mut self.packets, rng, self.config.max_duplicates)
+                    }
+                    Mutations::RemovePacket => chain::remove(&mut self.packets, rng, self.protocol),
+                    Mutations::Splice => chain::splice(
+                        &mut self.packets,
+                        &*packet_queue.read().await,
+                        rng,
+                        self.config.max_packets,
+                    ),
+                    Mutations::Crossover => chain::crossover(
+                        &mut self.packets,
+                        &*packet_queue.read().await,
+                        rng,
+                        self.config.max_packets,
+                    ),
+                    Mutations::Property(t) => fields::properties(&mut self.packets, rng, t),
+                    Mutations::Tls(t) => {
+                        if let Some(layer) = self.stream.tls_layer() {
+                            if let Err(e) = tls::apply(t, layer, &self.packets, rng).await {
+                                trace!("TLS mutation failed: {:?}", e);
+                            }
+                        }
                     }
                 }
                 self.state = State::MUTATION;
             }
             State::SEND => {
                 self.previous_packets.push(self.packets.clone());
-                let res =
-                    send_packets(&mut self.stream, &self.packets, packet_queue, self.timeout).await;
-                if let Err(e) = res {
-                    match e {
-                        SendError::Timeout => {}
-                        SendError::ReceiveErr => {
-                            trace!("Receive error, probably disconnected by the broker...")
-                        }
-                        SendError::SendErr => {
-                            trace!("Send error, probably disconnected by the broker...")
-                        }
+                let wire_mutation =
+                    match rng.gen_range(0f32..1f32) < self.config.wire_mutation_chance {
+                        true => Some(rng.gen::<WireMutation>()),
+                        false => None,
+                    };
+                let novelty = packet_queue.read().await.novelty;
+                let exchange = match &wire_mutation {
+                    Some(mutation) => {
+                        wire::send_packets(
+                            mutation,
+                            &mut self.stream,
+                            &self.packets,
+                            novelty,
+                            self.timeout,
+                            &self.config,
+                            rng,
+                        )
+                        .await
+                    }
+                    None => {
+                        send_packets(&mut self.stream, &self.packets, novelty, self.timeout).await
+                    }
+                };
+                match exchange.closed {
+                    Some(SendError::ReceiveErr) => {
+                        trace!("Receive error, probably disconnected by the broker...")
+                    }
+                    Some(SendError::SendErr) => {
+                        trace!("Send error, probably disconnected by the broker...")
+                    }
+                    None => trace!("Sent packet successfully"),
+                }
+                let new_behavior =
+                    new_behavior(&exchange, &self.packets, packet_queue, &self.feedback).await;
+                if new_behavior {
+                    // New responses may contain topics, reason strings etc. worth sending back
+                    for response in &exchange.responses {
+                        self.dictionary
+                            .extract_from_packet(response, self.protocol.mqtt_version());
                     }
-                } else {
-                    trace!("Sent packet successfully");
                 }
-                if rng.gen_range(0f32..1f32) > MUT_AFTER_SEND || res.is_err() {
+                if let Some(scheduler) = &self.scheduler {
+                    scheduler.record(&self.mutations, &self.packet_types, new_behavior);
+                }
+                // Every operator is only counted for the first send after it was used
+                self.mutations.clear();
+                self.packet_types.clear();
+                // Nothing can be sent after a half-close
+                if rng.gen_range(0f32..1f32) > self.config.mut_after_send
+                    || exchange.closed.is_some()
+                    || wire_mutation == Some(WireMutation::HalfClose)
+                {
                     self.state = State::Sf;
                 } else {
-                    self.state = State::Mutate(rng.gen());
+                    self.state = State::Mutate(self.sample_mutation(rng));
                 }
             }
             _ => todo!(),
         }
     }
-}
-
-#[derive(Debug, Clone, PartialEq, Eq, Hash)]
-enum BOF {
-    BOF,
-    NOBOF,
+    fn add_connect(&self) -> State {
+        match self.protocol {
+            Protocol::Mqtt | Protocol::Mqtt5 => State::ADD(PacketType::CONNECT),
+            Protocol::MqttSn => State::AddSn(SnPacketType::CONNECT),
+        }
+    }
+    fn sample_mutation(&mut self, rng: &mut Xoshiro256PlusPlus) -> Mutations {
+        if self.stream.tls_layer().is_some()
+            && rng.gen_range(0f32..1f32) < self.config.tls_mutation_chance
+        {
+            return Mutations::Tls(rng.gen());
+        }
+        match self.protocol {
+            Protocol::Mqtt => self.config.sample_mutation(rng, false),
+            Protocol::Mqtt5 if rng.gen_range(0f32..1f32) < self.config.property_mutation_chance => {
+                Mutations::Property(rng.gen())
+            }
+            Protocol::Mqtt5 => self.config.sample_mutation(rng, false),
+            // The length fixes and field mutations only understand MQTT packets
+            Protocol::MqttSn => self.config.sample_mutation(rng, true),
+        }
+    }
 }
 
 /// The MQTT Packet types
-#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+#[derive(Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord, Serialize, Deserialize)]
 pub enum PacketType {
     AUTH,
     CONNACK,
@@ -279,17 +576,28 @@ pub enum PacketType {
     UNSUBSCRIBE,
 }
 
-// Implement a distribution for the packet types
-impl Distribution<PacketType> for rand::distributions::Standard {
-    fn sample<R: Rng + ?Sized>(&self, rng: &mut R) -> PacketType {
-        match rng.gen_range(0..6) {
-            0 => PacketType::CONNECT,
-            1 => PacketType::PUBLISH,
-            2 => PacketType::SUBSCRIBE,
-            3 => PacketType::UNSUBSCRIBE,
-            4 => PacketType::PINGREQ,
-            5 => PacketType::DISCONNECT,
-            _ => unreachable!(),
-        }
+impl FromStr for PacketType {
+    type Err = color_eyre::Report;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(match s {
+            "AUTH" => PacketType::AUTH,
+            "CONNACK" => PacketType::CONNACK,
+            "CONNECT" => PacketType::CONNECT,
+            "DISCONNECT" => PacketType::DISCONNECT,
+            "PINGREQ" => PacketType::PINGREQ,
+            "PINGRESP" => PacketType::PINGRESP,
+            "PUBACK" => PacketType::PUBACK,
+            "PUBCOMP" => PacketType::PUBCOMP,
+            "PUBLISH" => PacketType::PUBLISH,
+            "PUBREC" => PacketType::PUBREC,
+            "PUBREL" => PacketType::PUBREL,
+            "RESERVED" => PacketType::RESERVED,
+            "SUBACK" => PacketType::SUBACK,
+            "SUBSCRIBE" => PacketType::SUBSCRIBE,
+            "UNSUBACK" => PacketType::UNSUBACK,
+            "UNSUBSCRIBE" => PacketType::UNSUBSCRIBE,
+            _ => bail!("Unknown packet type {s}"),
+        })
     }
 }
@@ -1,118 +1,475 @@
+use crate::dictionary::Dictionary;
+use crate::mqtt::decode::chain_version;
+use crate::mqtt::length;
 use crate::packets::Packets;
-use rand::distributions::Standard;
-use rand::prelude::Distribution;
+use rand::seq::SliceRandom;
 use rand::Rng;
 use rand_xoshiro::Xoshiro256PlusPlus;
+use serde::{Deserialize, Serialize};
 
-pub fn inject(packets: &mut Packets, rng: &mut Xoshiro256PlusPlus, inject_type: &InjectType) {
-    let packets_size = packets.size();
-    debug_assert!(packets_size > 0, "Packet size should be greater than 0");
-    let packet = packets
-        .inner
-        .get_mut(rng.gen_range(0..packets_size))
-        .unwrap();
-    match inject_type {
+// The largest change of the arithmetic mutation, the same as in AFL
+const ARITH_MAX: u32 = 35;
+// Havoc stacks 2 to 2^HAVOC_MAX_STACK_POW2 mutations
+const HAVOC_MAX_STACK_POW2: u32 = 5;
+// The longest block the block mutations copy or delete
+const MAX_BLOCK: usize = 32;
+
+/// Values which are likely to hit boundaries: zero, the sign bit of a byte, the maximum of a byte
+/// and of a two byte length, and the largest Remaining Length encoded as variable byte integer
+const INTERESTING_VALUES: [&[u8]; 5] = [
+    &[0],
+    &[0x7F],
+    &[0xFF],
+    &[0xFF, 0xFF],
+    &[0xFF, 0xFF, 0xFF, 0x7F],
+];
+
+pub fn inject(
+    packets: &mut Packets,
+    rng: &mut Xoshiro256PlusPlus,
+    inject_type: &InjectType,
+    length_fix: &LengthFix,
+) {
+    mutate_packet(packets, rng, length_fix, |packet, rng| match inject_type {
         InjectType::Single => inject_single(packet, rng),
         InjectType::BOF => inject_bof(packet, rng),
-    }
+    });
 }
 
-#[derive(Debug, Clone, PartialEq, Eq, Hash)]
-pub enum InjectType {
-    Single,
-    BOF,
+/// Which length fields are repaired after a byte-level mutation. Keeping the broken lengths tests
+/// the length checks of the broker, repairing them gets the mutation past those checks.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum LengthFix {
+    // Leave the packet as the mutation left it
+    Keep,
+    // Recompute the Remaining Length of the fixed header
+    RemainingLength,
+    // Also adjust the length prefix of the mutated string
+    All,
 }
 
-impl Distribution<InjectType> for Standard {
-    fn sample<R: Rng + ?Sized>(&self, rng: &mut R) -> InjectType {
-        match rng.gen_range(0..2) {
-            0 => InjectType::Single,
-            1 => InjectType::BOF,
-            _ => unreachable!(),
+/// Applies the byte-level mutation to a random packet of the chain and repairs the length fields
+/// afterwards. The mutation returns the index of the byte it mutated.
+fn mutate_packet(
+    packets: &mut Packets,
+    rng: &mut Xoshiro256PlusPlus,
+    length_fix: &LengthFix,
+    mutation: impl FnOnce(&mut Vec<u8>, &mut Xoshiro256PlusPlus) -> usize,
+) {
+    if packets.is_empty() {
+        return;
+    }
+    let version = chain_version(&packets.inner);
+    let index = rng.gen_range(0..packets.len());
+    let packet = &mut packets.inner[index];
+    let fields = match length_fix {
+        LengthFix::All => length::string_fields(packet, version),
+        _ => Vec::new(),
+    };
+    let old_len = packet.len() as isize;
+    let idx = mutation(packet, rng);
+    let delta = packet.len() as isize - old_len;
+    match length_fix {
+        LengthFix::Keep => {}
+        LengthFix::RemainingLength => length::fix_remaining_length(packet),
+        LengthFix::All => {
+            length::fix_string_length(packet, &fields, idx, delta);
+            length::fix_remaining_length(packet);
         }
     }
 }
 
-fn inject_bof(packet: &mut Vec<u8>, rng: &mut Xoshiro256PlusPlus) {
-    let idx = rng.gen_range(0..packet.len());
+#[allow(clippy::upper_case_acronyms)]
+#[derive(Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum InjectType {
+    Single,
+    #[serde(rename = "bof")]
+    BOF,
+}
+
+fn inject_bof(packet: &mut Vec<u8>, rng: &mut Xoshiro256PlusPlus) -> usize {
+    let idx = rng.gen_range(0..=packet.len());
     // To fight big packets
-    let byte_length = 350 / packet.len();
+    let byte_length = 350 / packet.len().max(1);
     let mut bytes = vec![0; byte_length];
     rng.fill(&mut bytes[..]);
     packet.splice(idx..idx, bytes);
+    idx
 }
 
-fn inject_single(packet: &mut Vec<u8>, rng: &mut Xoshiro256PlusPlus) {
-    let idx = rng.gen_range(0..packet.len());
+fn inject_single(packet: &mut Vec<u8>, rng: &mut Xoshiro256PlusPlus) -> usize {
+    let idx = rng.gen_range(0..=packet.len());
     let byte = rng.gen::<u8>();
     packet.insert(idx, byte);
+    idx
+}
+
+pub fn delete(packets: &mut Packets, rng: &mut Xoshiro256PlusPlus, length_fix: &LengthFix) {
+    mutate_packet(packets, rng, length_fix, delete_byte);
+}
+
+pub fn swap(packets: &mut Packets, rng: &mut Xoshiro256PlusPlus, length_fix: &LengthFix) {
+    mutate_packet(packets, rng, length_fix, |packet, rng| {
+        swap_byte(packet, rng)
+    });
 }
 
-pub fn delete(packets: &mut Packets, rng: &mut Xoshiro256PlusPlus) {
-    let packets_size = packets.size();
-    let packet = packets
-        .inner
-        .get_mut(rng.gen_range(0..packets_size))
-        .unwrap();
+pub fn bit_flip(packets: &mut Packets, rng: &mut Xoshiro256PlusPlus, length_fix: &LengthFix) {
+    mutate_packet(packets, rng, length_fix, |packet, rng| {
+        flip_bit(packet, rng)
+    });
+}
+
+pub fn nibble_flip(packets: &mut Packets, rng: &mut Xoshiro256PlusPlus, length_fix: &LengthFix) {
+    mutate_packet(packets, rng, length_fix, |packet, rng| {
+        flip_nibble(packet, rng)
+    });
+}
+
+pub fn arithmetic(packets: &mut Packets, rng: &mut Xoshiro256PlusPlus, length_fix: &LengthFix) {
+    mutate_packet(packets, rng, length_fix, |packet, rng| {
+        add_subtract(packet, rng)
+    });
+}
+
+pub fn interesting_value(
+    packets: &mut Packets,
+    rng: &mut Xoshiro256PlusPlus,
+    length_fix: &LengthFix,
+) {
+    mutate_packet(packets, rng, length_fix, overwrite_interesting);
+}
+
+pub fn block_duplicate(
+    packets: &mut Packets,
+    rng: &mut Xoshiro256PlusPlus,
+    length_fix: &LengthFix,
+) {
+    mutate_packet(packets, rng, length_fix, duplicate_block);
+}
+
+pub fn block_overwrite(
+    packets: &mut Packets,
+    rng: &mut Xoshiro256PlusPlus,
+    length_fix: &LengthFix,
+) {
+    mutate_packet(packets, rng, length_fix, |packet, rng| {
+        overwrite_block(packet, rng)
+    });
+}
+
+pub fn block_delete(packets: &mut Packets, rng: &mut Xoshiro256PlusPlus, length_fix: &LengthFix) {
+    mutate_packet(packets, rng, length_fix, delete_block);
+}
+
+/// Inserts a token of the dictionary at a random offset or writes it over the bytes there
+pub fn dictionary(
+    packets: &mut Packets,
+    rng: &mut Xoshiro256PlusPlus,
+    dictionary: &Dictionary,
+    length_fix: &LengthFix,
+) {
+    let Some(token) = dictionary.choose(rng) else {
+        return;
+    };
+    mutate_packet(packets, rng, length_fix, |packet, rng| {
+        insert_token(packet, rng, &token)
+    });
+}
+
+fn insert_token(packet: &mut Vec<u8>, rng: &mut Xoshiro256PlusPlus, token: &[u8]) -> usize {
+    let idx = rng.gen_range(0..=packet.len());
+    let end = match rng.gen_bool(0.5) {
+        true => idx,
+        false => (idx + token.len()).min(packet.len()),
+    };
+    packet.splice(idx..end, token.iter().copied());
+    idx
+}
+
+/// Stacks several random byte mutations on the same packet, like the havoc stage of AFL. The
+/// length fields are only repaired once, at the first mutated byte
+pub fn havoc(packets: &mut Packets, rng: &mut Xoshiro256PlusPlus, length_fix: &LengthFix) {
+    mutate_packet(packets, rng, length_fix, |packet, rng| {
+        let rounds = 1 << rng.gen_range(1..=HAVOC_MAX_STACK_POW2);
+        let mut first = None;
+        for _ in 0..rounds {
+            let idx = match rng.gen_range(0..10) {
+                0 => flip_bit(packet, rng),
+                1 => flip_nibble(packet, rng),
+                2 => add_subtract(packet, rng),
+                3 => overwrite_interesting(packet, rng),
+                4 => duplicate_block(packet, rng),
+                5 => overwrite_block(packet, rng),
+                6 => delete_block(packet, rng),
+                7 => inject_single(packet, rng),
+                8 => delete_byte(packet, rng),
+                9 => swap_byte(packet, rng),
+                _ => unreachable!(),
+            };
+            first.get_or_insert(idx);
+        }
+        first.unwrap_or_default()
+    });
+}
+
+fn delete_byte(packet: &mut Vec<u8>, rng: &mut Xoshiro256PlusPlus) -> usize {
+    // Empty packets stay in the chain, there is nothing left to delete
+    if packet.is_empty() {
+        return 0;
+    }
     let idx = rng.gen_range(0..packet.len());
     packet.remove(idx);
+    idx
 }
 
-pub fn swap(packets: &mut Packets, rng: &mut Xoshiro256PlusPlus) {
-    let packets_size = packets.size();
-    let packet = packets
-        .inner
-        .get_mut(rng.gen_range(0..packets_size))
-        .unwrap();
+fn swap_byte(packet: &mut [u8], rng: &mut Xoshiro256PlusPlus) -> usize {
+    if packet.is_empty() {
+        return 0;
+    }
     let idx = rng.gen_range(0..packet.len());
     let byte = rng.gen::<u8>();
     packet[idx] = byte;
+    idx
+}
+
+fn flip_bit(packet: &mut [u8], rng: &mut Xoshiro256PlusPlus) -> usize {
+    if packet.is_empty() {
+        return 0;
+    }
+    let idx = rng.gen_range(0..packet.len());
+    packet[idx] ^= 1 << rng.gen_range(0..8);
+    idx
+}
+
+fn flip_nibble(packet: &mut [u8], rng: &mut Xoshiro256PlusPlus) -> usize {
+    if packet.is_empty() {
+        return 0;
+    }
+    let idx = rng.gen_range(0..packet.len());
+    packet[idx] ^= if rng.gen_bool(0.5) { 0x0F } else { 0xF0 };
+    idx
+}
+
+/// Adds or subtracts a small value from a 1, 2 or 4 byte big endian number
+fn add_subtract(packet: &mut [u8], rng: &mut Xoshiro256PlusPlus) -> usize {
+    let width = *[1, 2, 4].choose(rng).unwrap();
+    if packet.len() < width {
+        return 0;
+    }
+    let idx = rng.gen_range(0..=packet.len() - width);
+    let mut bytes = [0; 4];
+    bytes[4 - width..].copy_from_slice(&packet[idx..idx + width]);
+    let value = u32::from_be_bytes(bytes);
+    let delta = rng.gen_range(1..=ARITH_MAX);
+    let value = if rng.gen_bool(0.5) {
+        value.wrapping_add(delta)
+    } else {
+        value.wrapping_sub(delta)
+    };
+    packet[idx..idx + width].copy_from_slice(&value.to_be_bytes()[4 - width..]);
+    idx
+}
+
+/// Overwrites bytes with an interesting value. Values reaching over the end extend the packet
+fn overwrite_interesting(packet: &mut Vec<u8>, rng: &mut Xoshiro256PlusPlus) -> usize {
+    let value = INTERESTING_VALUES.choose(rng).unwrap();
+    let idx = rng.gen_range(0..=packet.len());
+    let end = (idx + value.len()).min(packet.len());
+    packet.splice(idx..end, value.iter().copied());
+    idx
+}
+
+/// A random block of the packet, as start and length
+fn block(packet: &[u8], rng: &mut Xoshiro256PlusPlus) -> (usize, usize) {
+    let start = rng.gen_range(0..packet.len());
+    let len = rng.gen_range(1..=MAX_BLOCK.min(packet.len() - start));
+    (start, len)
+}
+
+/// Inserts a copy of a block at a random position
+fn duplicate_block(packet: &mut Vec<u8>, rng: &mut Xoshiro256PlusPlus) -> usize {
+    if packet.is_empty() {
+        return 0;
+    }
+    let (start, len) = block(packet, rng);
+    let copy = packet[start..start + len].to_vec();
+    let idx = rng.gen_range(0..=packet.len());
+    packet.splice(idx..idx, copy);
+    idx
+}
+
+/// Overwrites a block with another block of the packet or with a repeated random byte
+fn overwrite_block(packet: &mut [u8], rng: &mut Xoshiro256PlusPlus) -> usize {
+    if packet.is_empty() {
+        return 0;
+    }
+    let (start, len) = block(packet, rng);
+    let idx = rng.gen_range(0..=packet.len() - len);
+    if rng.gen_bool(0.5) {
+        packet.copy_within(start..start + len, idx);
+    } else {
+        packet[idx..idx + len].fill(rng.gen());
+    }
+    idx
+}
+
+fn delete_block(packet: &mut Vec<u8>, rng: &mut Xoshiro256PlusPlus) -> usize {
+    if packet.is_empty() {
+        return 0;
+    }
+    let (start, len) = block(packet, rng);
+    packet.drain(start..start + len);
+    start
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
-    use rand::prelude::thread_rng;
-    /*
+    use crate::mqtt::generate_publish_packet;
+    use rand::SeedableRng;
+
     #[test]
     fn test_inject_bof() {
-        let mut rng = thread_rng();
-        let mut packet = vec![0; 10];
-        println!("Input packet: {:?}", packet);
-        inject_bof(&mut packet, &mut rng);
-        println!("Output packet: {:?}", packet);
-        assert!(packet.len() > 10);
-        assert!(packet.len() < 30);
+        let mut rng = Xoshiro256PlusPlus::seed_from_u64(0);
+        let original: Vec<u8> = (0..10).collect();
+        let mut packet = original.clone();
+        let idx = inject_bof(&mut packet, &mut rng);
+        // The bytes are inserted at idx, the original ones around them are kept
+        assert!(packet.len() > original.len());
+        assert_eq!(packet[..idx], original[..idx]);
+        assert_eq!(
+            packet[packet.len() - (original.len() - idx)..],
+            original[idx..]
+        );
     }
 
     #[test]
     fn test_inject_single() {
-        let mut rng = thread_rng();
+        let mut rng = Xoshiro256PlusPlus::seed_from_u64(0);
         let mut packet = vec![0; 10];
-        println!("Input packet: {:?}", packet);
         inject_single(&mut packet, &mut rng);
-        println!("Output packet: {:?}", packet);
         assert_eq!(packet.len(), 11);
     }
 
     #[test]
     fn test_swap() {
-        let mut rng = thread_rng();
-        let mut packet = vec![0; 10];
-        println!("Input packet: {:?}", packet);
-        swap(&mut packet, &mut rng);
-        println!("Output packet: {:?}", packet);
-        assert_eq!(packet.len(), 10);
+        let mut rng = Xoshiro256PlusPlus::seed_from_u64(0);
+        let mut packets = Packets::new();
+        packets.append(&[0; 10]);
+        swap(&mut packets, &mut rng, &LengthFix::Keep);
+        assert_eq!(packets.inner[0].len(), 10);
     }
 
     #[test]
     fn test_delete() {
-        let mut rng = thread_rng();
-        let mut packet = vec![0; 10];
-        println!("Input packet: {:?}", packet);
-        delete(&mut packet, &mut rng);
-        println!("Output packet: {:?}", packet);
-        assert!(packet.len() < 10);
-        assert!(packet.len() > 0);
-    }*/
+        let mut rng = Xoshiro256PlusPlus::seed_from_u64(0);
+        let mut packets = Packets::new();
+        packets.append(&[0; 10]);
+        delete(&mut packets, &mut rng, &LengthFix::Keep);
+        assert_eq!(packets.inner[0].len(), 9);
+        // Packets which are deleted completely stay in the chain and can be mutated further
+        let mut packets = Packets::new();
+        packets.append(&[0]);
+        delete(&mut packets, &mut rng, &LengthFix::Keep);
+        delete(&mut packets, &mut rng, &LengthFix::All);
+        swap(&mut packets, &mut rng, &LengthFix::RemainingLength);
+        assert_eq!(packets.inner, vec![Vec::<u8>::new()]);
+        inject(&mut packets, &mut rng, &InjectType::BOF, &LengthFix::Keep);
+        assert_eq!(packets.inner[0].len(), 350);
+    }
+
+    #[test]
+    fn test_length_fix() {
+        let mut rng = Xoshiro256PlusPlus::seed_from_u64(0);
+        for _ in 0..100 {
+            let mut packets = Packets::new();
+            packets.append(&generate_publish_packet(&mut rng));
+            inject(
+                &mut packets,
+                &mut rng,
+                &InjectType::BOF,
+                &LengthFix::RemainingLength,
+            );
+            let packet = &packets.inner[0];
+            // The mutation may have hit the fixed header itself
+            if let Some((remaining_length, size)) = length::decode_remaining_length(packet) {
+                assert_eq!(remaining_length, packet.len() - 1 - size);
+            }
+        }
+    }
+
+    #[test]
+    fn test_classic_mutations() {
+        let mut rng = Xoshiro256PlusPlus::seed_from_u64(0);
+        for _ in 0..100 {
+            let mut packet = vec![0x30, 4, 0, 1, 0x41, 0x42];
+            flip_bit(&mut packet, &mut rng);
+            let differences: u32 = packet
+                .iter()
+                .zip([0x30, 4, 0, 1, 0x41, 0x42])
+                .map(|(a, b)| (a ^ b).count_ones())
+                .sum();
+            assert_eq!(differences, 1);
+            flip_nibble(&mut packet, &mut rng);
+            add_subtract(&mut packet, &mut rng);
+            overwrite_block(&mut packet, &mut rng);
+            assert_eq!(packet.len(), 6);
+            duplicate_block(&mut packet, &mut rng);
+            assert!(packet.len() > 6);
+            let len = packet.len();
+            delete_block(&mut packet, &mut rng);
+            assert!(packet.len() < len);
+        }
+        // The value is written over the packet or appended to it
+        for _ in 0..100 {
+            let mut packet = vec![1, 1];
+            overwrite_interesting(&mut packet, &mut rng);
+            assert!((2..=6).contains(&packet.len()));
+            assert!(INTERESTING_VALUES
+                .iter()
+                .any(|value| packet.windows(value.len()).any(|window| window == *value)));
+        }
+    }
+
+    #[test]
+    fn test_havoc() {
+        let mut rng = Xoshiro256PlusPlus::seed_from_u64(0);
+        for _ in 0..100 {
+            let mut packets = Packets::new();
+            packets.append(&generate_publish_packet(&mut rng));
+            havoc(&mut packets, &mut rng, &LengthFix::RemainingLength);
+            let packet = &packets.inner[0];
+            if let Some((remaining_length, size)) = length::decode_remaining_length(packet) {
+                assert_eq!(remaining_length, packet.len() - 1 - size);
+            }
+        }
+        // Empty packets survive all mutations
+        let mut packets = Packets::new();
+        packets.append(&[]);
+        for _ in 0..100 {
+            havoc(&mut packets, &mut rng, &LengthFix::All);
+            packets.inner[0].clear();
+        }
+    }
+
+    #[test]
+    fn test_dictionary() {
+        let mut rng = Xoshiro256PlusPlus::seed_from_u64(0);
+        let tokens = Dictionary::default();
+        for _ in 0..100 {
+            let mut packets = Packets::new();
+            let packet = generate_publish_packet(&mut rng);
+            packets.append(&packet);
+            dictionary(&mut packets, &mut rng, &tokens, &LengthFix::RemainingLength);
+            assert!(packets.inner[0].len() > packet.len() || packets.inner[0] != packet);
+            if let Some((remaining_length, size)) =
+                length::decode_remaining_length(&packets.inner[0])
+            {
+                assert_eq!(remaining_length, packets.inner[0].len() - 1 - size);
+            }
+        }
+    }
 }
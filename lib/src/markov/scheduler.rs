@@ -0,0 +1,224 @@
+//! Adaptive scheduling of the mutations and packet types, similar to MOpt. Every send records which
+//! mutations and packet types built the chain and whether the broker showed a new behavior. From
+//! time to time the weights of the [MarkovConfig] are recomputed, so productive operators are
+//! sampled more often. Some exploration is kept, so no operator ever dies out.
+//! TLS and property mutations have a fixed chance and are not scheduled. Neither are MQTT-SN packet
+//! types, which are sampled uniformly, only the mutations of MQTT-SN chains are.
+use crate::markov::config::MarkovConfig;
+use crate::markov::{MutationKind, PacketType};
+use color_eyre::Result;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use tokio::fs;
+
+/// The learned weights are written here
+pub const SCHEDULER_FILE: &str = "./scheduler.toml";
+// Number of sends between two weight updates
+const UPDATE_INTERVAL: u64 = 1000;
+// Share of the configured weight every operator keeps, however unproductive it is
+const EXPLORATION: f32 = 0.2;
+// The most an operator can be favored over its configured weight
+const MAX_BOOST: f32 = 10.0;
+// How many uses an operator needs before its own find rate outweighs the mean rate
+const PRIOR_USES: f32 = 10.0;
+
+/// How often an operator was part of a sent chain and how often that chain found a new behavior
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+pub struct OperatorStats {
+    pub uses: u64,
+    pub finds: u64,
+    /// The current sampling weight
+    pub weight: f32,
+}
+
+impl OperatorStats {
+    // Rarely used operators are pulled towards the mean rate, so a single find doesn't decide
+    fn efficiency(&self, mean_rate: f32) -> f32 {
+        (self.finds as f32 + mean_rate * PRIOR_USES) / (self.uses as f32 + PRIOR_USES)
+    }
+}
+
+/// The statistics which are written to disk
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct SchedulerStats {
+    pub sends: u64,
+    pub mutations: BTreeMap<MutationKind, OperatorStats>,
+    pub packet_types: BTreeMap<PacketType, OperatorStats>,
+}
+
+#[derive(Debug)]
+pub struct Scheduler {
+    // The configuration the weights are learned from
+    base: Arc<MarkovConfig>,
+    current: Mutex<Arc<MarkovConfig>>,
+    stats: Mutex<SchedulerStats>,
+    adaptive: bool,
+}
+
+impl Scheduler {
+    /// Always hands out the configuration as it is
+    pub fn fixed(config: Arc<MarkovConfig>) -> Self {
+        Self::new(config, false)
+    }
+
+    /// Learns the weights of the mutations and packet types while fuzzing
+    pub fn adaptive(config: Arc<MarkovConfig>) -> Self {
+        Self::new(config, true)
+    }
+
+    fn new(config: Arc<MarkovConfig>, adaptive: bool) -> Self {
+        let stats = SchedulerStats {
+            sends: 0,
+            mutations: initial_stats(&config.mutations),
+            packet_types: initial_stats(&config.packet_types),
+        };
+        Self {
+            base: config.clone(),
+            current: Mutex::new(config),
+            stats: Mutex::new(stats),
+            adaptive,
+        }
+    }
+
+    /// The configuration with the current weights
+    pub fn config(&self) -> Arc<MarkovConfig> {
+        self.current.lock().unwrap().clone()
+    }
+
+    pub fn stats(&self) -> SchedulerStats {
+        self.stats.lock().unwrap().clone()
+    }
+
+    /// Records the operators of a sent chain
+    pub(crate) fn record(
+        &self,
+        mutations: &[MutationKind],
+        packet_types: &[PacketType],
+        new_behavior: bool,
+    ) {
+        if !self.adaptive {
+            return;
+        }
+        let mut stats = self.stats.lock().unwrap();
+        stats.sends += 1;
+        for mutation in mutations {
+            count(stats.mutations.entry(*mutation).or_default(), new_behavior);
+        }
+        for packet_type in packet_types {
+            count(
+                stats.packet_types.entry(packet_type.clone()).or_default(),
+                new_behavior,
+            );
+        }
+        if stats.sends.is_multiple_of(UPDATE_INTERVAL) {
+            let mut config = (*self.base).clone();
+            config.mutations = learn_weights(&self.base.mutations, &mut stats.mutations);
+            config.packet_types = learn_weights(&self.base.packet_types, &mut stats.packet_types);
+            *self.current.lock().unwrap() = Arc::new(config);
+        }
+    }
+
+    pub async fn write_to_file(&self, path: impl AsRef<Path>) -> Result<()> {
+        let serialized = toml::to_string(&self.stats())?;
+        fs::write(path, serialized).await?;
+        Ok(())
+    }
+}
+
+fn initial_stats<K: Ord + Clone>(weights: &BTreeMap<K, f32>) -> BTreeMap<K, OperatorStats> {
+    weights
+        .iter()
+        .map(|(key, weight)| {
+            let stats = OperatorStats {
+                weight: *weight,
+                ..Default::default()
+            };
+            (key.clone(), stats)
+        })
+        .collect()
+}
+
+fn count(stats: &mut OperatorStats, new_behavior: bool) {
+    stats.uses += 1;
+    if new_behavior {
+        stats.finds += 1;
+    }
+}
+
+/// Scales the configured weights by the efficiency of the operator relative to the mean
+/// efficiency. Operators which are disabled in the configuration stay disabled
+fn learn_weights<K: Ord + Clone>(
+    base: &BTreeMap<K, f32>,
+    stats: &mut BTreeMap<K, OperatorStats>,
+) -> BTreeMap<K, f32> {
+    let enabled: Vec<_> = base.iter().filter(|(_, weight)| **weight > 0.0).collect();
+    if enabled.is_empty() {
+        return base.clone();
+    }
+    let uses: u64 = stats.values().map(|stats| stats.uses).sum();
+    let finds: u64 = stats.values().map(|stats| stats.finds).sum();
+    let mean_rate = finds as f32 / uses.max(1) as f32;
+    let efficiencies: Vec<f32> = enabled
+        .iter()
+        .map(|(key, _)| {
+            let stats = stats.get(key).copied().unwrap_or_default();
+            stats.efficiency(mean_rate)
+        })
+        .collect();
+    let mean = efficiencies.iter().sum::<f32>() / enabled.len() as f32;
+    let mut weights = base.clone();
+    for ((key, base_weight), efficiency) in enabled.into_iter().zip(efficiencies) {
+        // Nothing was found so far, so there is nothing to learn from
+        let boost = match mean > 0.0 {
+            true => (efficiency / mean).min(MAX_BOOST),
+            false => 1.0,
+        };
+        let weight = base_weight * (EXPLORATION + (1.0 - EXPLORATION) * boost);
+        weights.insert(key.clone(), weight);
+        stats.entry(key.clone()).or_default().weight = weight;
+    }
+    weights
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_productive_operators_gain_weight() {
+        let scheduler = Scheduler::adaptive(Arc::new(MarkovConfig::default()));
+        for i in 0..UPDATE_INTERVAL {
+            // Topic mutations find something every time, swaps never do
+            scheduler.record(&[MutationKind::Topic], &[PacketType::PUBLISH], true);
+            if i % 2 == 0 {
+                scheduler.record(&[MutationKind::Swap], &[PacketType::CONNECT], false);
+            }
+        }
+        let config = scheduler.config();
+        let topic = config.mutations[&MutationKind::Topic];
+        let swap = config.mutations[&MutationKind::Swap];
+        let inject = config.mutations[&MutationKind::Inject];
+        assert!(topic > inject && inject > swap, "{:?}", config.mutations);
+        // Unproductive operators are still sampled
+        assert!(swap >= EXPLORATION * MarkovConfig::default().mutations[&MutationKind::Swap]);
+        assert!(
+            config.packet_types[&PacketType::PUBLISH] > config.packet_types[&PacketType::CONNECT]
+        );
+        let stats = scheduler.stats();
+        assert_eq!(stats.mutations[&MutationKind::Topic].finds, UPDATE_INTERVAL);
+        assert_eq!(stats.mutations[&MutationKind::Topic].weight, topic);
+    }
+
+    #[test]
+    fn test_fixed_scheduler() {
+        let config = Arc::new(MarkovConfig::default());
+        let scheduler = Scheduler::fixed(config.clone());
+        for _ in 0..UPDATE_INTERVAL {
+            scheduler.record(&[MutationKind::Topic], &[], true);
+        }
+        assert_eq!(scheduler.config(), config);
+        assert_eq!(scheduler.stats().sends, 0);
+    }
+}
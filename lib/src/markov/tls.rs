@@ -0,0 +1,169 @@
+//! Mutations of the TLS layer below the MQTT stream. These bypass the TLS session and write
+//! records directly to the socket, so brokers see broken records mixed into an otherwise valid
+//! session.
+use crate::network::TlsLayer;
+use crate::packets::Packets;
+use rand::distributions::Standard;
+use rand::prelude::Distribution;
+use rand::Rng;
+use rand_xoshiro::Xoshiro256PlusPlus;
+use std::io;
+
+const CHANGE_CIPHER_SPEC: u8 = 20;
+const ALERT: u8 = 21;
+const HANDSHAKE: u8 = 22;
+const APPLICATION_DATA: u8 = 23;
+const TLS_1_2: u16 = 0x0303;
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum TlsMutation {
+    // A record with random content type, version, length and payload
+    MalformedRecord,
+    // A record whose payload is shorter than its length field
+    TruncatedRecord,
+    // A close_notify alert in the middle of the session
+    CloseNotify,
+    // An unencrypted ClientHello after the handshake is done
+    Renegotiation,
+    // A packet of the chain sent as an unencrypted application data record
+    EarlyData,
+}
+
+impl Distribution<TlsMutation> for Standard {
+    fn sample<R: Rng + ?Sized>(&self, rng: &mut R) -> TlsMutation {
+        match rng.gen_range(0..5) {
+            0 => TlsMutation::MalformedRecord,
+            1 => TlsMutation::TruncatedRecord,
+            2 => TlsMutation::CloseNotify,
+            3 => TlsMutation::Renegotiation,
+            4 => TlsMutation::EarlyData,
+            _ => unreachable!(),
+        }
+    }
+}
+
+/// Applies the mutation to the TLS layer of the current connection
+pub async fn apply(
+    mutation: &TlsMutation,
+    layer: &mut dyn TlsLayer,
+    packets: &Packets,
+    rng: &mut Xoshiro256PlusPlus,
+) -> io::Result<()> {
+    match mutation {
+        TlsMutation::MalformedRecord => layer.write_raw(&malformed_record(rng)).await,
+        TlsMutation::TruncatedRecord => layer.write_raw(&truncated_record(rng)).await,
+        TlsMutation::CloseNotify => layer.close_notify().await,
+        TlsMutation::Renegotiation => layer.write_raw(&client_hello(rng)).await,
+        TlsMutation::EarlyData => {
+            let packet = match packets.len() {
+                0 => Vec::new(),
+                len => packets.inner[rng.gen_range(0..len)].clone(),
+            };
+            layer
+                .write_raw(&record(APPLICATION_DATA, TLS_1_2, &packet))
+                .await
+        }
+    }
+}
+
+fn record(content_type: u8, version: u16, payload: &[u8]) -> Vec<u8> {
+    let mut record = Vec::with_capacity(payload.len() + 5);
+    record.push(content_type);
+    record.extend_from_slice(&version.to_be_bytes());
+    record.extend_from_slice(&(payload.len() as u16).to_be_bytes());
+    record.extend_from_slice(payload);
+    record
+}
+
+fn random_bytes(rng: &mut Xoshiro256PlusPlus, max_len: usize) -> Vec<u8> {
+    let mut bytes = vec![0; rng.gen_range(0..=max_len)];
+    rng.fill(&mut bytes[..]);
+    bytes
+}
+
+fn malformed_record(rng: &mut Xoshiro256PlusPlus) -> Vec<u8> {
+    let content_type = match rng.gen_range(0..5) {
+        0 => CHANGE_CIPHER_SPEC,
+        1 => ALERT,
+        2 => HANDSHAKE,
+        3 => APPLICATION_DATA,
+        _ => rng.gen(),
+    };
+    let version = match rng.gen_range(0..3) {
+        0 => 0x0301,
+        1 => TLS_1_2,
+        _ => rng.gen(),
+    };
+    let mut record = record(content_type, version, &random_bytes(rng, 64));
+    // Lie about the length
+    let length: u16 = rng.gen();
+    record[3..5].copy_from_slice(&length.to_be_bytes());
+    record
+}
+
+fn truncated_record(rng: &mut Xoshiro256PlusPlus) -> Vec<u8> {
+    let content_type = if rng.gen_bool(0.5) {
+        APPLICATION_DATA
+    } else {
+        HANDSHAKE
+    };
+    let payload = random_bytes(rng, 64);
+    let mut record = record(content_type, TLS_1_2, &payload);
+    let length = rng.gen_range(payload.len() as u16 + 1..=u16::MAX);
+    record[3..5].copy_from_slice(&length.to_be_bytes());
+    record
+}
+
+fn client_hello(rng: &mut Xoshiro256PlusPlus) -> Vec<u8> {
+    let mut body = Vec::new();
+    body.extend_from_slice(&TLS_1_2.to_be_bytes());
+    let mut random = [0u8; 32];
+    rng.fill(&mut random);
+    body.extend_from_slice(&random);
+    // Empty session id
+    body.push(0);
+    // TLS_AES_128_GCM_SHA256 and TLS_ECDHE_RSA_WITH_AES_128_GCM_SHA256
+    body.extend_from_slice(&[0, 4, 0x13, 0x01, 0xc0, 0x2f]);
+    // Null compression
+    body.extend_from_slice(&[1, 0]);
+    let extensions = random_bytes(rng, 32);
+    body.extend_from_slice(&(extensions.len() as u16).to_be_bytes());
+    body.extend_from_slice(&extensions);
+    let mut handshake = vec![1];
+    handshake.extend_from_slice(&(body.len() as u32).to_be_bytes()[1..]);
+    handshake.extend_from_slice(&body);
+    record(HANDSHAKE, TLS_1_2, &handshake)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::SeedableRng;
+
+    #[test]
+    fn test_truncated_record() {
+        let mut rng = Xoshiro256PlusPlus::seed_from_u64(0);
+        for _ in 0..100 {
+            let record = truncated_record(&mut rng);
+            let length = u16::from_be_bytes([record[3], record[4]]) as usize;
+            assert!(length > record.len() - 5);
+        }
+    }
+
+    #[test]
+    fn test_client_hello() {
+        let mut rng = Xoshiro256PlusPlus::seed_from_u64(0);
+        let record = client_hello(&mut rng);
+        assert_eq!(record[0], HANDSHAKE);
+        assert_eq!(
+            u16::from_be_bytes([record[3], record[4]]) as usize,
+            record.len() - 5
+        );
+        // The handshake message is a ClientHello with a correct length
+        assert_eq!(record[5], 1);
+        assert_eq!(
+            u32::from_be_bytes([0, record[6], record[7], record[8]]) as usize,
+            record.len() - 9
+        );
+    }
+}
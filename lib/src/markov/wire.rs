@@ -0,0 +1,248 @@
+//! Mutations of how the chain is written to the socket instead of what is written. Brokers which
+//! assume one read per packet break when packets are split into many segments, coalesced into one
+//! write, trickled in past the keep alive or cut off by a half-close.
+use crate::markov::config::MarkovConfig;
+use crate::markov::ByteStream;
+use crate::mqtt::signature::Novelty;
+use crate::mqtt::{Exchange, SendError};
+use crate::packets::Packets;
+use rand::distributions::Standard;
+use rand::prelude::Distribution;
+use rand::seq::SliceRandom;
+use rand::Rng;
+use rand_xoshiro::Xoshiro256PlusPlus;
+use std::time::Duration;
+use tokio::io::AsyncWriteExt;
+use tokio::time::sleep;
+use tracing::trace;
+
+// The most pieces a packet is split into
+const MAX_PIECES: usize = 8;
+// The most bytes which are trickled in one by one before the stall
+const MAX_TRICKLE: usize = 16;
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum WireMutation {
+    // Every packet is written in several pieces with delays in between
+    Split,
+    // Consecutive packets are written at once
+    Coalesce,
+    // A packet is written byte by byte and stalls in the middle
+    Slowloris,
+    // A packet is cut off and the write half of the connection is closed
+    HalfClose,
+}
+
+impl Distribution<WireMutation> for Standard {
+    fn sample<R: Rng + ?Sized>(&self, rng: &mut R) -> WireMutation {
+        match rng.gen_range(0..4) {
+            0 => WireMutation::Split,
+            1 => WireMutation::Coalesce,
+            2 => WireMutation::Slowloris,
+            3 => WireMutation::HalfClose,
+            _ => unreachable!(),
+        }
+    }
+}
+
+/// Sends the chain like [send_packets](crate::mqtt::send_packets), but changes how the bytes are
+/// written
+pub(crate) async fn send_packets(
+    mutation: &WireMutation,
+    stream: &mut impl ByteStream,
+    packets: &Packets,
+    novelty: Novelty,
+    timeout: u16,
+    config: &MarkovConfig,
+    rng: &mut Xoshiro256PlusPlus,
+) -> Exchange {
+    let chain = &packets.inner;
+    let mut exchange = Exchange::default();
+    match mutation {
+        WireMutation::Split => {
+            for packet in chain {
+                if packet.is_empty() {
+                    exchange.skip();
+                    continue;
+                }
+                for (i, piece) in split(packet, rng).into_iter().enumerate() {
+                    if i > 0 {
+                        delay(config, rng).await;
+                    }
+                    if !exchange.write(stream, piece, timeout).await {
+                        return exchange;
+                    }
+                }
+                if !exchange.receive(stream, novelty, timeout).await {
+                    return exchange;
+                }
+            }
+        }
+        WireMutation::Coalesce => {
+            let mut rest = chain.as_slice();
+            while !rest.is_empty() {
+                let count = rng.gen_range(2.min(rest.len())..=rest.len());
+                // The response belongs to the last packet of the write, the others get empty ones
+                for _ in 1..count {
+                    exchange.skip();
+                }
+                if !exchange
+                    .send(stream, &rest[..count].concat(), novelty, timeout)
+                    .await
+                {
+                    return exchange;
+                }
+                rest = &rest[count..];
+            }
+        }
+        WireMutation::Slowloris | WireMutation::HalfClose => {
+            let sent: Vec<usize> = (0..chain.len()).filter(|i| !chain[*i].is_empty()).collect();
+            let Some(&target) = sent.choose(rng) else {
+                chain.iter().for_each(|_| exchange.skip());
+                return exchange;
+            };
+            for packet in &chain[..target] {
+                if packet.is_empty() {
+                    exchange.skip();
+                } else if !exchange.send(stream, packet, novelty, timeout).await {
+                    return exchange;
+                }
+            }
+            let packet = &chain[target];
+            if *mutation == WireMutation::HalfClose {
+                let cut = rng.gen_range(0..packet.len());
+                if !exchange.write(stream, &packet[..cut], timeout).await {
+                    return exchange;
+                }
+                if let Err(e) = stream.shutdown().await {
+                    trace!("Half-close failed: {:?}", e);
+                    exchange.closed = Some(SendError::SendErr);
+                    return exchange;
+                }
+                exchange.receive(stream, novelty, timeout).await;
+                return exchange;
+            }
+            let trickle = (packet.len() - 1).min(MAX_TRICKLE);
+            for byte in &packet[..trickle] {
+                if !exchange.write(stream, &[*byte], timeout).await {
+                    return exchange;
+                }
+                delay(config, rng).await;
+            }
+            sleep(Duration::from_millis(config.stall_time)).await;
+            if !exchange
+                .send(stream, &packet[trickle..], novelty, timeout)
+                .await
+            {
+                return exchange;
+            }
+            for packet in &chain[target + 1..] {
+                if packet.is_empty() {
+                    exchange.skip();
+                } else if !exchange.send(stream, packet, novelty, timeout).await {
+                    return exchange;
+                }
+            }
+        }
+    }
+    exchange
+}
+
+/// Splits the packet at random offsets
+fn split<'a>(packet: &'a [u8], rng: &mut Xoshiro256PlusPlus) -> Vec<&'a [u8]> {
+    let mut offsets: Vec<usize> = (0..rng.gen_range(1..MAX_PIECES))
+        .map(|_| rng.gen_range(1..packet.len().max(2)))
+        .filter(|offset| *offset < packet.len())
+        .collect();
+    offsets.sort();
+    offsets.dedup();
+    let mut pieces = Vec::with_capacity(offsets.len() + 1);
+    let mut start = 0;
+    for offset in offsets {
+        pieces.push(&packet[start..offset]);
+        start = offset;
+    }
+    pieces.push(&packet[start..]);
+    pieces
+}
+
+async fn delay(config: &MarkovConfig, rng: &mut Xoshiro256PlusPlus) {
+    sleep(Duration::from_millis(
+        rng.gen_range(0..=config.max_wire_delay),
+    ))
+    .await;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::SeedableRng;
+    use tokio::io::{duplex, AsyncReadExt};
+
+    #[test]
+    fn test_split() {
+        let mut rng = Xoshiro256PlusPlus::seed_from_u64(0);
+        let packet = [0x30, 5, 0, 1, b'a', 1, 2];
+        for _ in 0..100 {
+            let pieces = split(&packet, &mut rng);
+            assert!(pieces.iter().all(|piece| !piece.is_empty()));
+            assert_eq!(pieces.concat(), packet);
+        }
+        assert_eq!(split(&[0xE0], &mut rng), vec![&[0xE0][..]]);
+    }
+
+    #[tokio::test]
+    async fn test_wire_mutations_keep_the_bytes() {
+        let config = MarkovConfig {
+            max_wire_delay: 1,
+            stall_time: 1,
+            ..Default::default()
+        };
+        let mut rng = Xoshiro256PlusPlus::seed_from_u64(0);
+        let mut packets = Packets::new();
+        packets.append(&[0x10, 2, 0, 0]);
+        packets.append(&[]);
+        packets.append(&[0xC0, 0]);
+        packets.append(&[0xE0, 0]);
+        for mutation in [
+            WireMutation::Split,
+            WireMutation::Coalesce,
+            WireMutation::Slowloris,
+            WireMutation::HalfClose,
+        ] {
+            let (mut client, mut broker) = duplex(1024);
+            // Acknowledges every read
+            let broker = tokio::spawn(async move {
+                let mut received = Vec::new();
+                let mut buf = [0; 1024];
+                while let Ok(n @ 1..) = broker.read(&mut buf).await {
+                    received.extend_from_slice(&buf[..n]);
+                    let _ = broker.write_all(&[0xD0, 0]).await;
+                }
+                received
+            });
+            let exchange = send_packets(
+                &mutation,
+                &mut client,
+                &packets,
+                Novelty::Signature,
+                100,
+                &config,
+                &mut rng,
+            )
+            .await;
+            if mutation != WireMutation::HalfClose {
+                assert_eq!(exchange.closed, None, "{mutation:?}");
+                // One response per packet, the empty one included
+                assert_eq!(exchange.responses.len(), packets.len(), "{mutation:?}");
+            }
+            drop(client);
+            let received = broker.await.unwrap();
+            let chain = packets.inner.concat();
+            match mutation {
+                WireMutation::HalfClose => assert!(chain.starts_with(&received)),
+                _ => assert_eq!(received, chain, "{mutation:?}"),
+            }
+        }
+    }
+}
@@ -0,0 +1,273 @@
+//! A lenient decoder for MQTT packets. It doesn't validate anything, it only finds where the fields
+//! are, so mutations can target them. Decoding stops at the first field which doesn't fit into the
+//! packet, so mutated packets are decoded as far as possible. Only CONNECT packets tell their
+//! protocol version, all other packets are decoded as the version they are sent with.
+use super::length::{decode_remaining_length, decode_variable_byte_integer};
+use super::MqttVersion;
+use std::ops::Range;
+
+pub(crate) const CONNECT: u8 = 1;
+pub(crate) const PUBLISH: u8 = 3;
+pub(crate) const SUBSCRIBE: u8 = 8;
+pub(crate) const UNSUBSCRIBE: u8 = 10;
+
+/// The positions of the fields of a packet
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub(crate) struct Layout {
+    /// The upper nibble of the first byte
+    pub(crate) packet_type: u8,
+    /// All length prefixed strings, as ranges of their content
+    pub(crate) strings: Vec<Range<usize>>,
+    /// Topic names of PUBLISH and topic filters of SUBSCRIBE and UNSUBSCRIBE
+    pub(crate) topics: Vec<Range<usize>>,
+    pub(crate) client_id: Option<Range<usize>>,
+    pub(crate) connect_flags: Option<usize>,
+    /// Offset of the two byte packet identifier
+    pub(crate) packet_id: Option<usize>,
+    /// Offsets of the options following each topic filter of a SUBSCRIBE
+    pub(crate) subscription_options: Vec<usize>,
+}
+
+/// The version the broker reads the chain as, the protocol level of its first CONNECT. Chains
+/// without a CONNECT are MQTT 3.1.1
+pub(crate) fn chain_version(packets: &[Vec<u8>]) -> MqttVersion {
+    let level = packets.iter().find_map(|packet| {
+        let layout = decode(packet, MqttVersion::V311)?;
+        let name = layout.strings.first()?;
+        packet
+            .get(name.end)
+            .filter(|_| layout.packet_type == CONNECT)
+    });
+    match level {
+        Some(5) => MqttVersion::V5,
+        _ => MqttVersion::V311,
+    }
+}
+
+/// Decodes the packet as sent with the version. CONNECT packets are decoded as the version they
+/// tell
+pub(crate) fn decode(packet: &[u8], version: MqttVersion) -> Option<Layout> {
+    let (_, size) = decode_remaining_length(packet)?;
+    let start = 1 + size;
+    let mut layout = Layout {
+        packet_type: packet[0] >> 4,
+        ..Default::default()
+    };
+    match layout.packet_type {
+        // Protocol name, then client id, will topic, will message, username and password depending
+        // on the connect flags
+        CONNECT => {
+            let Some(name) = read_string(packet, start, &mut layout.strings) else {
+                return Some(layout);
+            };
+            let Some(&flags) = packet.get(name.end + 1) else {
+                return Some(layout);
+            };
+            layout.connect_flags = Some(name.end + 1);
+            // MQTT 5 has properties in front of the client id and the will topic
+            let version5 = packet.get(name.end) == Some(&5);
+            // Skip protocol level, flags and keep alive
+            let mut offset = name.end + 4;
+            if version5 {
+                let Some(end) = skip_properties(packet, offset) else {
+                    return Some(layout);
+                };
+                offset = end;
+            }
+            let Some(client_id) = read_string(packet, offset, &mut layout.strings) else {
+                return Some(layout);
+            };
+            offset = client_id.end;
+            layout.client_id = Some(client_id);
+            let will = flags & 0b100 != 0;
+            if will && version5 {
+                let Some(end) = skip_properties(packet, offset) else {
+                    return Some(layout);
+                };
+                offset = end;
+            }
+            let present = [
+                will,
+                will,
+                flags & 0b1000_0000 != 0,
+                flags & 0b100_0000 != 0,
+            ];
+            for _ in present.into_iter().filter(|present| *present) {
+                match read_string(packet, offset, &mut layout.strings) {
+                    Some(field) => offset = field.end,
+                    None => break,
+                }
+            }
+        }
+        // Topic name, followed by the packet identifier if the QoS is greater than 0
+        PUBLISH => {
+            if let Some(topic) = read_string(packet, start, &mut layout.strings) {
+                if packet[0] & 0b110 != 0 && packet.len() >= topic.end + 2 {
+                    layout.packet_id = Some(topic.end);
+                }
+                layout.topics.push(topic);
+            }
+        }
+        // Packet identifier, then topic filters. SUBSCRIBE has options after every filter. MQTT 5
+        // has properties in front of the filters
+        SUBSCRIBE | UNSUBSCRIBE => {
+            if packet.len() >= start + 2 {
+                layout.packet_id = Some(start);
+            }
+            let mut offset = start + 2;
+            if version == MqttVersion::V5 {
+                let Some(end) = skip_properties(packet, offset) else {
+                    return Some(layout);
+                };
+                offset = end;
+            }
+            while let Some(topic) = read_string(packet, offset, &mut layout.strings) {
+                offset = topic.end;
+                if layout.packet_type == SUBSCRIBE && offset < packet.len() {
+                    layout.subscription_options.push(offset);
+                    offset += 1;
+                }
+                layout.topics.push(topic);
+            }
+        }
+        // PUBACK, PUBREC, PUBREL, PUBCOMP, SUBACK and UNSUBACK start with the packet identifier
+        4..=7 | 9 | 11 if packet.len() >= start + 2 => layout.packet_id = Some(start),
+        _ => {}
+    }
+    Some(layout)
+}
+
+/// Returns the end of the MQTT 5 property list starting at the offset
+fn skip_properties(packet: &[u8], offset: usize) -> Option<usize> {
+    let (length, size) = decode_variable_byte_integer(packet.get(offset..)?)?;
+    Some(offset + size + length)
+}
+
+fn read_string(
+    packet: &[u8],
+    offset: usize,
+    strings: &mut Vec<Range<usize>>,
+) -> Option<Range<usize>> {
+    let prefix = packet.get(offset..offset + 2)?;
+    let length = u16::from_be_bytes([prefix[0], prefix[1]]) as usize;
+    let field = offset + 2..offset + 2 + length;
+    if field.end > packet.len() {
+        return None;
+    }
+    strings.push(field.clone());
+    Some(field)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::markov::PacketType;
+    use crate::mqtt::{
+        generate_connect_packet, generate_publish_packet, generate_subscribe_packet,
+        generate_unsubscribe_packet, v5,
+    };
+    use rand::SeedableRng;
+    use rand_xoshiro::Xoshiro256PlusPlus;
+
+    #[test]
+    fn test_decode_connect() {
+        let mut rng = Xoshiro256PlusPlus::seed_from_u64(0);
+        for _ in 0..100 {
+            let connect = generate_connect_packet(&mut rng);
+            let layout = decode(&connect, MqttVersion::V311).unwrap();
+            let flags = connect[layout.connect_flags.unwrap()];
+            assert_eq!(&connect[layout.strings[0].clone()], b"MQTT");
+            assert_eq!(layout.client_id, Some(layout.strings[1].clone()));
+            // Protocol name, client id, will topic and message, username and password
+            let fields = 2
+                + 2 * usize::from(flags & 0b100 != 0)
+                + usize::from(flags & 0b1000_0000 != 0)
+                + usize::from(flags & 0b100_0000 != 0);
+            assert_eq!(layout.strings.len(), fields);
+            assert_eq!(layout.strings.last().unwrap().end, connect.len());
+        }
+    }
+
+    #[test]
+    fn test_decode_connect_v5() {
+        let mut rng = Xoshiro256PlusPlus::seed_from_u64(0);
+        for _ in 0..100 {
+            let connect = v5::generate_packet(&PacketType::CONNECT, &mut rng);
+            let layout = decode(&connect, MqttVersion::V311).unwrap();
+            // The client id is random, but prefixed by its length
+            let client_id = layout.client_id.unwrap();
+            let length = &connect[client_id.start - 2..client_id.start];
+            assert_eq!(
+                u16::from_be_bytes([length[0], length[1]]) as usize,
+                client_id.len()
+            );
+            // The properties are skipped, so the last field ends with the packet
+            assert_eq!(layout.strings.last().unwrap().end, connect.len());
+        }
+    }
+
+    #[test]
+    fn test_decode_topics() {
+        let mut rng = Xoshiro256PlusPlus::seed_from_u64(0);
+        for _ in 0..100 {
+            let publish = generate_publish_packet(&mut rng);
+            let layout = decode(&publish, MqttVersion::V311).unwrap();
+            assert_eq!(layout.topics.len(), 1);
+            // Only QoS 0 publishes have no packet identifier
+            assert_eq!(layout.packet_id.is_some(), publish[0] & 0b110 != 0);
+            let subscribe = generate_subscribe_packet(&mut rng);
+            let layout = decode(&subscribe, MqttVersion::V311).unwrap();
+            assert_eq!(layout.topics.len(), layout.subscription_options.len());
+            assert_eq!(
+                *layout.subscription_options.last().unwrap(),
+                subscribe.len() - 1
+            );
+            assert_eq!(layout.packet_id, Some(2));
+            let unsubscribe = generate_unsubscribe_packet(&mut rng);
+            let layout = decode(&unsubscribe, MqttVersion::V311).unwrap();
+            assert_eq!(layout.topics.last().unwrap().end, unsubscribe.len());
+            assert!(layout.subscription_options.is_empty());
+        }
+    }
+
+    #[test]
+    fn test_decode_topics_v5() {
+        let mut rng = Xoshiro256PlusPlus::seed_from_u64(0);
+        for _ in 0..100 {
+            let subscribe = v5::generate_packet(&PacketType::SUBSCRIBE, &mut rng);
+            let layout = decode(&subscribe, MqttVersion::V5).unwrap();
+            // The properties in front of the filters are skipped
+            assert!(!layout.topics.is_empty());
+            assert!(layout.topics.iter().all(|topic| !topic.is_empty()));
+            assert_eq!(layout.topics.len(), layout.subscription_options.len());
+            assert_eq!(
+                *layout.subscription_options.last().unwrap(),
+                subscribe.len() - 1
+            );
+            // Property lists can make the Remaining Length longer than one byte
+            let (_, size) = decode_remaining_length(&subscribe).unwrap();
+            assert_eq!(layout.packet_id, Some(1 + size));
+            let unsubscribe = v5::generate_packet(&PacketType::UNSUBSCRIBE, &mut rng);
+            let layout = decode(&unsubscribe, MqttVersion::V5).unwrap();
+            assert!(layout.topics.iter().all(|topic| !topic.is_empty()));
+            assert_eq!(layout.topics.last().unwrap().end, unsubscribe.len());
+        }
+    }
+
+    #[test]
+    fn test_chain_version() {
+        let mut rng = Xoshiro256PlusPlus::seed_from_u64(0);
+        let subscribe = generate_subscribe_packet(&mut rng);
+        let connect = generate_connect_packet(&mut rng);
+        let connect_v5 = v5::generate_packet(&PacketType::CONNECT, &mut rng);
+        assert_eq!(
+            chain_version(std::slice::from_ref(&subscribe)),
+            MqttVersion::V311
+        );
+        assert_eq!(
+            chain_version(&[connect, subscribe.clone()]),
+            MqttVersion::V311
+        );
+        assert_eq!(chain_version(&[subscribe, connect_v5]), MqttVersion::V5);
+    }
+}
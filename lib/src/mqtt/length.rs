@@ -0,0 +1,144 @@
+//! The length fields of MQTT packets: the variable length Remaining Length of the fixed header and
+//! the two byte length prefixes of strings. Used to repair packets after byte-level mutations.
+use super::decode::decode;
+use super::MqttVersion;
+use std::ops::Range;
+
+const MAX_REMAINING_LENGTH: usize = 268_435_455;
+
+/// Encodes the length as variable byte integer, like the Remaining Length or MQTT 5 property lengths
+pub(crate) fn encode_remaining_length(mut length: usize) -> Vec<u8> {
+    let mut bytes = Vec::with_capacity(4);
+    loop {
+        let mut byte = (length % 128) as u8;
+        length /= 128;
+        if length > 0 {
+            byte |= 128;
+        }
+        bytes.push(byte);
+        if length == 0 {
+            return bytes;
+        }
+    }
+}
+
+/// Decodes the Remaining Length following the first byte of the packet. Returns the length and
+/// the number of bytes it occupies, or None if the field is malformed
+pub(crate) fn decode_remaining_length(packet: &[u8]) -> Option<(usize, usize)> {
+    decode_variable_byte_integer(packet.get(1..)?)
+}
+
+/// Decodes the variable byte integer at the start of the bytes. Returns the value and the number of
+/// bytes it occupies
+pub(crate) fn decode_variable_byte_integer(bytes: &[u8]) -> Option<(usize, usize)> {
+    let mut length = 0;
+    for (i, byte) in bytes.iter().take(4).enumerate() {
+        length += ((byte & 127) as usize) << (7 * i);
+        if byte & 128 == 0 {
+            return Some((length, i + 1));
+        }
+    }
+    None
+}
+
+/// Sets the Remaining Length to the actual length of the packet. Packets with a malformed
+/// Remaining Length are left as they are
+pub(crate) fn fix_remaining_length(packet: &mut Vec<u8>) {
+    let Some((_, size)) = decode_remaining_length(packet) else {
+        return;
+    };
+    let length = packet.len() - 1 - size;
+    if length > MAX_REMAINING_LENGTH {
+        return;
+    }
+    packet.splice(1..1 + size, encode_remaining_length(length));
+}
+
+/// The length prefixed strings of the packet as ranges of their content. The prefixes are the two
+/// bytes in front of each range.
+pub(crate) fn string_fields(packet: &[u8], version: MqttVersion) -> Vec<Range<usize>> {
+    decode(packet, version)
+        .map(|layout| layout.strings)
+        .unwrap_or_default()
+}
+
+/// Adjusts the length prefix of the string which contains the mutated byte. `fields` have to be
+/// taken from the packet before it was mutated.
+pub(crate) fn fix_string_length(
+    packet: &mut [u8],
+    fields: &[Range<usize>],
+    mutated: usize,
+    delta: isize,
+) {
+    let Some(field) = fields.iter().find(|field| field.contains(&mutated)) else {
+        return;
+    };
+    let length = field.len() as isize + delta;
+    if !(0..=u16::MAX as isize).contains(&length) {
+        return;
+    }
+    packet[field.start - 2..field.start].copy_from_slice(&(length as u16).to_be_bytes());
+}
+
+/// Replaces the content of the string and updates its length prefix and the Remaining Length
+pub(crate) fn replace_string(packet: &mut Vec<u8>, field: Range<usize>, content: &[u8]) {
+    let length = content.len().min(u16::MAX as usize) as u16;
+    packet[field.start - 2..field.start].copy_from_slice(&length.to_be_bytes());
+    packet.splice(field, content[..length as usize].iter().copied());
+    fix_remaining_length(packet);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_remaining_length() {
+        for length in [
+            0,
+            127,
+            128,
+            16_383,
+            16_384,
+            2_097_151,
+            2_097_152,
+            MAX_REMAINING_LENGTH,
+        ] {
+            let mut packet = vec![0x30];
+            packet.extend(encode_remaining_length(length));
+            assert_eq!(
+                decode_remaining_length(&packet),
+                Some((length, packet.len() - 1))
+            );
+        }
+        assert_eq!(encode_remaining_length(321), vec![193, 2]);
+        assert_eq!(
+            decode_remaining_length(&[0x30, 255, 255, 255, 255, 1]),
+            None
+        );
+    }
+
+    #[test]
+    fn test_fix_remaining_length() {
+        let mut packet = vec![0x30, 0];
+        packet.extend([0; 200]);
+        fix_remaining_length(&mut packet);
+        assert_eq!(&packet[..3], &[0x30, 200, 1]);
+        assert_eq!(packet.len(), 203);
+        packet.truncate(10);
+        fix_remaining_length(&mut packet);
+        assert_eq!(packet, vec![0x30, 7, 0, 0, 0, 0, 0, 0, 0]);
+    }
+
+    #[test]
+    fn test_fix_string_length() {
+        // Subscription to "topic" with packet identifier 100
+        let mut packet = vec![130, 10, 0, 100, 0, 5, 116, 111, 112, 105, 99, 0];
+        let fields = string_fields(&packet, MqttVersion::V311);
+        packet.insert(8, b'x');
+        fix_string_length(&mut packet, &fields, 8, 1);
+        fix_remaining_length(&mut packet);
+        assert_eq!(&packet[..7], &[130, 11, 0, 100, 0, 6, b't']);
+        assert_eq!(string_fields(&packet, MqttVersion::V311)[0].len(), 6);
+    }
+}
@@ -1,32 +1,182 @@
-use crate::markov::ByteStream;
+pub(crate) mod decode;
+pub(crate) mod length;
+pub mod signature;
+pub mod v5;
+
+use crate::markov::{ByteStream, PacketType};
+use crate::mqtt::signature::Novelty;
 use crate::packets::{PacketQueue, Packets};
+use crate::process_monitor::Feedback;
+use clap::ValueEnum;
+use rand::distributions::Alphanumeric;
+use rand::seq::SliceRandom;
+use rand::{Rng, SeedableRng};
+use rand_xoshiro::Xoshiro256PlusPlus;
 use std::sync::Arc;
 use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
 use tokio::sync::RwLock;
 use tokio::time::timeout;
 use tracing::{debug, info, trace};
 
-pub(crate) fn generate_connect_packet() -> [u8; 62] {
-    [
-        16, 60, 0, 4, 77, 81, 84, 84, 4, 4, 0, 0, 0, 17, 72, 101, 108, 108, 111, 32, 77, 81, 84,
-        84, 32, 66, 114, 111, 107, 101, 114, 0, 5, 116, 111, 112, 105, 99, 0, 22, 1, 2, 3, 4, 5, 6,
-        7, 8, 9, 10, 11, 12, 13, 14, 15, 16, 72, 255, 50, 0, 0, 0,
-    ]
+/// The MQTT version of the generated packets
+#[derive(ValueEnum, Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum MqttVersion {
+    #[default]
+    #[value(name = "3.1.1")]
+    V311,
+    #[value(name = "5")]
+    V5,
+}
+
+// Once the received packets are complete, more are only waited for this fraction of the timeout
+const FOLLOW_UP_DIVISOR: u16 = 10;
+// Brokers which flood the connection aren't read any further
+const MAX_RESPONSE: usize = 1 << 20;
+const TOPIC_LEVELS: [&str; 8] = ["topic", "a", "b", "sensor", "temperature", "home", "1", ""];
+
+pub(crate) fn push_string(bytes: &mut Vec<u8>, string: &[u8]) {
+    bytes.extend_from_slice(&(string.len() as u16).to_be_bytes());
+    bytes.extend_from_slice(string);
+}
+
+/// Prepends the fixed header
+pub(crate) fn finish_packet(first_byte: u8, body: Vec<u8>) -> Vec<u8> {
+    let mut packet = vec![first_byte];
+    packet.extend(length::encode_remaining_length(body.len()));
+    packet.extend(body);
+    packet
+}
+
+pub(crate) fn random_bytes(rng: &mut Xoshiro256PlusPlus, max_len: usize) -> Vec<u8> {
+    let mut bytes = vec![0; rng.gen_range(0..=max_len)];
+    rng.fill(&mut bytes[..]);
+    bytes
+}
+
+/// A random valid client id. Empty ids are only valid with a clean session
+pub(crate) fn generate_client_id(rng: &mut Xoshiro256PlusPlus) -> Vec<u8> {
+    match rng.gen_range(0..3) {
+        0 => Vec::new(),
+        1 => format!("rusty-FUME-{}", rng.gen::<u16>()).into_bytes(),
+        _ => (0..rng.gen_range(1..=23))
+            .map(|_| rng.sample(Alphanumeric))
+            .collect(),
+    }
+}
+
+/// A topic name, or a topic filter which may contain wildcards
+pub(crate) fn generate_topic(rng: &mut Xoshiro256PlusPlus, filter: bool) -> Vec<u8> {
+    let levels = rng.gen_range(1..=4);
+    let mut topic: Vec<&str> = (0..levels)
+        .map(|_| {
+            if filter && rng.gen_bool(0.2) {
+                "+"
+            } else {
+                TOPIC_LEVELS.choose(rng).unwrap()
+            }
+        })
+        .collect();
+    if filter && rng.gen_bool(0.2) {
+        topic.push("#");
+    }
+    let topic = topic.join("/");
+    // Empty topics are not allowed
+    if topic.is_empty() {
+        b"topic".to_vec()
+    } else {
+        topic.into_bytes()
+    }
 }
 
-pub(crate) fn generate_publish_packet() -> [u8; 27] {
-    [
-        49, 25, 0, 5, 116, 111, 112, 105, 99, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 72, 255, 50,
-        0, 0, 0,
-    ]
+pub(crate) fn generate_connect_packet(rng: &mut Xoshiro256PlusPlus) -> Vec<u8> {
+    let client_id = generate_client_id(rng);
+    let will = rng.gen_bool(0.5);
+    let username = rng.gen_bool(0.3);
+    // A password needs a username in MQTT 3.1.1
+    let password = username && rng.gen_bool(0.5);
+    let mut flags = 0;
+    if client_id.is_empty() || rng.gen_bool(0.5) {
+        flags |= 0b10;
+    }
+    if will {
+        flags |= 0b100 | rng.gen_range(0..=2) << 3;
+        if rng.gen_bool(0.5) {
+            flags |= 0b10_0000;
+        }
+    }
+    if username {
+        flags |= 0b1000_0000;
+    }
+    if password {
+        flags |= 0b100_0000;
+    }
+    let mut body = Vec::new();
+    push_string(&mut body, b"MQTT");
+    body.push(4);
+    body.push(flags);
+    body.extend_from_slice(&generate_keep_alive(rng).to_be_bytes());
+    push_string(&mut body, &client_id);
+    if will {
+        push_string(&mut body, &generate_topic(rng, false));
+        push_string(&mut body, &random_bytes(rng, 32));
+    }
+    if username {
+        push_string(&mut body, b"user");
+    }
+    if password {
+        push_string(&mut body, &generate_password(rng));
+    }
+    finish_packet(0x10, body)
 }
 
-pub(crate) fn generate_subscribe_packet() -> [u8; 12] {
-    [130, 10, 0, 100, 0, 5, 116, 111, 112, 105, 99, 0]
+/// No keep alive, the common default, the maximum or anything in between
+pub(crate) fn generate_keep_alive(rng: &mut Xoshiro256PlusPlus) -> u16 {
+    match rng.gen_range(0..4) {
+        0 => 0,
+        1 => 60,
+        2 => u16::MAX,
+        _ => rng.gen(),
+    }
 }
 
-pub(crate) fn generate_unsubscribe_packet() -> [u8; 11] {
-    [162, 9, 0, 10, 0, 5, 116, 111, 112, 105, 99]
+pub(crate) fn generate_password(rng: &mut Xoshiro256PlusPlus) -> Vec<u8> {
+    (0..rng.gen_range(0..16))
+        .map(|_| rng.sample(Alphanumeric))
+        .collect()
+}
+
+pub(crate) fn generate_publish_packet(rng: &mut Xoshiro256PlusPlus) -> Vec<u8> {
+    let qos: u8 = rng.gen_range(0..=2);
+    let mut first_byte = 0x30 | qos << 1 | u8::from(rng.gen_bool(0.2));
+    // DUP may only be set for QoS > 0
+    if qos > 0 && rng.gen_bool(0.2) {
+        first_byte |= 0b1000;
+    }
+    let mut body = Vec::new();
+    push_string(&mut body, &generate_topic(rng, false));
+    if qos > 0 {
+        body.extend_from_slice(&rng.gen_range(1..=u16::MAX).to_be_bytes());
+    }
+    body.extend(random_bytes(rng, 64));
+    finish_packet(first_byte, body)
+}
+
+pub(crate) fn generate_subscribe_packet(rng: &mut Xoshiro256PlusPlus) -> Vec<u8> {
+    let mut body = rng.gen_range(1..=u16::MAX).to_be_bytes().to_vec();
+    for _ in 0..rng.gen_range(1..=4) {
+        push_string(&mut body, &generate_topic(rng, true));
+        body.push(rng.gen_range(0..=2));
+    }
+    finish_packet(0x82, body)
+}
+
+pub(crate) fn generate_unsubscribe_packet(rng: &mut Xoshiro256PlusPlus) -> Vec<u8> {
+    let mut body = rng.gen_range(1..=u16::MAX).to_be_bytes().to_vec();
+    for _ in 0..rng.gen_range(1..=4) {
+        push_string(&mut body, &generate_topic(rng, true));
+    }
+    finish_packet(0xA2, body)
 }
 
 pub(crate) fn generate_disconnect_packet() -> [u8; 2] {
@@ -37,9 +187,71 @@ pub(crate) fn generate_pingreq_packet() -> [u8; 2] {
     [192, 0]
 }
 
+/// Small identifiers are likely in use by the session, large ones most likely aren't
+pub(crate) fn generate_packet_id(rng: &mut Xoshiro256PlusPlus) -> [u8; 2] {
+    if rng.gen_bool(0.5) {
+        rng.gen_range(1..=10u16).to_be_bytes()
+    } else {
+        rng.gen_range(1..=u16::MAX).to_be_bytes()
+    }
+}
+
+/// The packets which only consist of a packet identifier: PUBACK, PUBREC, PUBREL, PUBCOMP and
+/// UNSUBACK
+fn generate_ack_packet(first_byte: u8, rng: &mut Xoshiro256PlusPlus) -> Vec<u8> {
+    finish_packet(first_byte, generate_packet_id(rng).to_vec())
+}
+
+fn generate_connack_packet(rng: &mut Xoshiro256PlusPlus) -> Vec<u8> {
+    // Session present and a return code, 0 to 5 are defined
+    finish_packet(0x20, vec![rng.gen_range(0..=1), rng.gen_range(0..=5)])
+}
+
+fn generate_suback_packet(rng: &mut Xoshiro256PlusPlus) -> Vec<u8> {
+    let mut body = generate_packet_id(rng).to_vec();
+    for _ in 0..rng.gen_range(1..=4) {
+        body.push(*[0, 1, 2, 0x80].choose(rng).unwrap());
+    }
+    finish_packet(0x90, body)
+}
+
+/// Packet type 15, which is reserved in MQTT 3.1.1, in the form of an MQTT 5 AUTH
+fn generate_auth_packet(rng: &mut Xoshiro256PlusPlus) -> Vec<u8> {
+    finish_packet(0xF0, vec![*[0x00, 0x18, 0x19].choose(rng).unwrap(), 0])
+}
+
+/// The reserved packet types 0 and 15 with random flags and content
+pub(crate) fn generate_reserved_packet(rng: &mut Xoshiro256PlusPlus) -> Vec<u8> {
+    let packet_type = if rng.gen_bool(0.5) { 0x00 } else { 0xF0 };
+    finish_packet(packet_type | rng.gen_range(0..16), random_bytes(rng, 16))
+}
+
+/// Generates a packet of the type, including packets only the broker should send
+pub(crate) fn generate_packet(packet_type: &PacketType, rng: &mut Xoshiro256PlusPlus) -> Vec<u8> {
+    match packet_type {
+        PacketType::AUTH => generate_auth_packet(rng),
+        PacketType::CONNACK => generate_connack_packet(rng),
+        PacketType::CONNECT => generate_connect_packet(rng),
+        PacketType::DISCONNECT => generate_disconnect_packet().to_vec(),
+        PacketType::PINGREQ => generate_pingreq_packet().to_vec(),
+        PacketType::PINGRESP => vec![0xD0, 0],
+        PacketType::PUBACK => generate_ack_packet(0x40, rng),
+        PacketType::PUBCOMP => generate_ack_packet(0x70, rng),
+        PacketType::PUBLISH => generate_publish_packet(rng),
+        PacketType::PUBREC => generate_ack_packet(0x50, rng),
+        PacketType::PUBREL => generate_ack_packet(0x62, rng),
+        PacketType::RESERVED => generate_reserved_packet(rng),
+        PacketType::SUBACK => generate_suback_packet(rng),
+        PacketType::SUBSCRIBE => generate_subscribe_packet(rng),
+        PacketType::UNSUBACK => generate_ack_packet(0xB0, rng),
+        PacketType::UNSUBSCRIBE => generate_unsubscribe_packet(rng),
+    }
+}
+
 pub async fn test_connection(stream: &mut impl ByteStream) -> color_eyre::Result<()> {
+    let mut rng = Xoshiro256PlusPlus::from_entropy();
     stream
-        .write_all(generate_connect_packet().as_slice())
+        .write_all(generate_connect_packet(&mut rng).as_slice())
         .await?;
     let mut buf = [0; 1024];
     let _ = timeout(Duration::from_secs(1), stream.read(&mut buf)).await;
@@ -48,164 +260,250 @@ pub async fn test_connection(stream: &mut impl ByteStream) -> color_eyre::Result
 }
 #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
 pub(crate) enum SendError {
-    // Probably a DOS discovered. The server didn't respond in time
-    Timeout,
     // The server crashed. As the connection is closed or similar
     ReceiveErr,
     // We couldn't send the packet. A previous packet might have crashed the server
     SendErr,
 }
 
+/// Everything the broker sent while the chain was sent
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub(crate) struct Exchange {
+    // The bytes received after each packet. A packet without a response has an empty one
+    pub(crate) responses: Vec<Vec<u8>>,
+    // Set if the connection was closed, right after the last response
+    pub(crate) closed: Option<SendError>,
+}
+
+impl Exchange {
+    /// Records an empty response for a packet which isn't sent, so response N stays the one to
+    /// packet N
+    pub(crate) fn skip(&mut self) {
+        self.responses.push(Vec::new());
+    }
+
+    /// Writes the packet and receives the response. Returns false once the connection is closed
+    pub(crate) async fn send(
+        &mut self,
+        stream: &mut impl ByteStream,
+        packet: &[u8],
+        novelty: Novelty,
+        timeout_ms: u16,
+    ) -> bool {
+        self.write(stream, packet, timeout_ms).await
+            && self.receive(stream, novelty, timeout_ms).await
+    }
+
+    /// Writes the bytes without waiting for a response. Returns false once the connection is closed
+    pub(crate) async fn write(
+        &mut self,
+        stream: &mut impl ByteStream,
+        bytes: &[u8],
+        timeout_ms: u16,
+    ) -> bool {
+        match write_packet(stream, bytes, timeout_ms).await {
+            Ok(()) => true,
+            Err(e) => {
+                self.closed = Some(e);
+                false
+            }
+        }
+    }
+
+    /// Reads until the broker is silent for the timeout or, once the received packets are
+    /// complete, for a much shorter time. Returns false once the connection is closed
+    pub(crate) async fn receive(
+        &mut self,
+        stream: &mut impl ByteStream,
+        novelty: Novelty,
+        timeout_ms: u16,
+    ) -> bool {
+        let mut response = Vec::new();
+        let mut buf = [0; 4096];
+        let open = loop {
+            let wait = match !response.is_empty() && novelty.is_complete(&response) {
+                true => (timeout_ms / FOLLOW_UP_DIVISOR).max(1),
+                false => timeout_ms,
+            };
+            match timeout(Duration::from_millis(wait as u64), stream.read(&mut buf)).await {
+                Ok(Ok(0)) => break false,
+                Ok(Ok(n)) => response.extend_from_slice(&buf[..n]),
+                Err(_) => break true,
+                Ok(Err(e)) => {
+                    trace!("Receive error: {:?}", e);
+                    break false;
+                }
+            }
+            if response.len() >= MAX_RESPONSE {
+                break true;
+            }
+        };
+        self.responses.push(response);
+        if !open {
+            self.closed = Some(SendError::ReceiveErr);
+        }
+        open
+    }
+}
+
+/// Sends the packets one after another and collects the responses, until the broker closes the
+/// connection
 pub(crate) async fn send_packets(
     stream: &mut impl ByteStream,
     packets: &Packets,
-    packet_queue: &Arc<RwLock<PacketQueue>>,
+    novelty: Novelty,
     timeout: u16,
-) -> Result<(), SendError> {
-    for packet in packets.inner.iter().filter(|p| !p.is_empty()) {
-        send_packet(stream, packet.as_slice(), packets, packet_queue, timeout).await?;
+) -> Exchange {
+    let mut exchange = Exchange::default();
+    for packet in &packets.inner {
+        // Mutations can empty a packet, there is nothing to send then
+        if packet.is_empty() {
+            exchange.skip();
+            continue;
+        }
+        if !exchange.send(stream, packet, novelty, timeout).await {
+            break;
+        }
     }
-    Ok(())
+    exchange
 }
 
-pub(crate) async fn send_packet(
+/// Writes the bytes, a timeout is not an error as the broker may just be slow to read
+pub(crate) async fn write_packet(
     stream: &mut impl ByteStream,
-    packet: &[u8],
-    packets: &Packets,
-    packet_queue: &Arc<RwLock<PacketQueue>>,
+    bytes: &[u8],
     timeout_ms: u16,
 ) -> Result<(), SendError> {
     let write_result = timeout(
         Duration::from_millis(timeout_ms as u64),
-        stream.write_all(packet),
+        stream.write_all(bytes),
     )
     .await;
     match write_result {
-        Ok(Ok(_)) => (),
+        Ok(Ok(_)) => Ok(()),
         Err(t) => {
             trace!("Timeout: {:?}", t);
-        }
-        Ok(Err(e)) => {
-            trace!("Send error: {:?}", e);
-            return Err(SendError::SendErr);
-        }
-    }
-    let mut buf = [0; 1024];
-    let res = timeout(
-        Duration::from_millis(timeout_ms as u64),
-        stream.read(&mut buf),
-    )
-    .await;
-    match res {
-        Ok(Ok(p)) => {
-            known_packet(&buf[..p], packets, packet_queue).await;
             Ok(())
         }
-        Err(t) => {
-            trace!("Timeout: {:?}", t);
-            // TODO: Retry sending the packet
-            Err(SendError::Timeout)
-        }
         Ok(Err(e)) => {
-            trace!("Receive error: {:?}", e);
-            Err(SendError::ReceiveErr)
+            trace!("Send error: {:?}", e);
+            Err(SendError::SendErr)
         }
     }
 }
 
-/// This works by using the response packet as the key in a hashmap. If the packet is already in the hashmap we know that we have seen it before
-async fn known_packet(
-    response_packet: &[u8],
+/// This works by using the fingerprint of the responses as the key in a map. If the fingerprint is
+/// already in the map we know that we have seen the behavior before. New behaviors are added to the
+/// queue with the chain which caused them. New edges in the broker and new log templates are a new
+/// behavior as well
+pub(crate) async fn new_behavior(
+    exchange: &Exchange,
     input_packet: &Packets,
     packet_queue: &Arc<RwLock<PacketQueue>>,
+    feedback: &Feedback,
 ) -> bool {
-    // TODO: decode the packet and extract user id, payload, topic etc. because those don't matter to see if it is a known packet
     let queue_lock = packet_queue.read().await;
-    let response_packet = response_packet.to_vec();
-    if !queue_lock.inner.contains_key(&response_packet) {
+    let mut key = queue_lock
+        .novelty
+        .fingerprint(&exchange.responses, exchange.closed.is_some());
+    key.extend(feedback.collect());
+    if !queue_lock.inner.contains_key(&key) {
         info!("New behavior discovered, adding it to the queue",);
-        debug!("Response packet: {:?}", response_packet);
+        debug!("Responses: {:?}", exchange);
         drop(queue_lock);
         let mut queue_lock = packet_queue.write().await;
-        queue_lock
-            .inner
-            .insert(response_packet, input_packet.clone());
+        queue_lock.inner.insert(key, input_packet.clone());
+        return true;
     }
     trace!(
         "Known behavior. We have {} known behaviors",
-        packet_queue.read().await.inner.len()
+        queue_lock.inner.len()
     );
     false
 }
 #[cfg(test)]
 mod tests {
     use super::*;
-    use mqtt::packet::QoSWithPacketIdentifier;
-    use mqtt::{Encodable, TopicFilter, TopicName};
-    // To not generate these packets over and over again during execution of the markov model, we generate them here and then use them in the functions
-    #[test]
-    fn generate_connect_packet() {
-        let mut connect = mqtt::packet::ConnectPacket::new("Hello MQTT Broker");
-        connect.set_will(Some((
-            TopicName::new("topic").unwrap(),
-            vec![
-                1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15, 16, 72, 255, 50, 0, 0, 0,
-            ],
-        )));
-        let mut packet = Vec::new();
-        connect.encode(&mut packet).unwrap();
-        println!("{packet:?}");
-    }
+    use mqtt::packet::VariablePacket;
+    use mqtt::Decodable;
+    use std::io::Cursor;
 
-    #[test]
-    fn generate_publish_packet() {
-        let mut publish = mqtt::packet::PublishPacket::new(
-            TopicName::new("topic").unwrap(),
-            QoSWithPacketIdentifier::Level0,
-            vec![1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 72, 255, 50, 0, 0, 0],
-        );
-        publish.set_retain(true);
-        let mut packet = Vec::new();
-        publish.encode(&mut packet).unwrap();
-        println!("{packet:?}");
-    }
-
-    #[test]
-    fn generate_subscribe_packet() {
-        let subscribe = mqtt::packet::SubscribePacket::new(
-            100,
-            vec![(
-                TopicFilter::new("topic").unwrap(),
-                mqtt::QualityOfService::Level0,
-            )],
-        );
-        let mut packet = Vec::new();
-        subscribe.encode(&mut packet).unwrap();
-        println!("{packet:?}");
-    }
-
-    #[test]
-    fn generate_unsubscribe_packet() {
-        let unsubscribe =
-            mqtt::packet::UnsubscribePacket::new(10, vec![TopicFilter::new("topic").unwrap()]);
-        let mut packet = Vec::new();
-        unsubscribe.encode(&mut packet).unwrap();
-        println!("{packet:?}");
-    }
+    pub(crate) const PACKET_TYPES: [PacketType; 16] = [
+        PacketType::AUTH,
+        PacketType::CONNACK,
+        PacketType::CONNECT,
+        PacketType::DISCONNECT,
+        PacketType::PINGREQ,
+        PacketType::PINGRESP,
+        PacketType::PUBACK,
+        PacketType::PUBCOMP,
+        PacketType::PUBLISH,
+        PacketType::PUBREC,
+        PacketType::PUBREL,
+        PacketType::RESERVED,
+        PacketType::SUBACK,
+        PacketType::SUBSCRIBE,
+        PacketType::UNSUBACK,
+        PacketType::UNSUBSCRIBE,
+    ];
 
     #[test]
-    fn generate_disconnect_packet() {
-        let disconnect = mqtt::packet::DisconnectPacket::new();
-        let mut packet = Vec::new();
-        disconnect.encode(&mut packet).unwrap();
-        println!("{packet:?}");
+    fn test_generated_packets_are_valid() {
+        let mut rng = Xoshiro256PlusPlus::seed_from_u64(0);
+        for _ in 0..1000 {
+            for packet_type in PACKET_TYPES.iter() {
+                let packet = generate_packet(packet_type, &mut rng);
+                let (remaining_length, size) = length::decode_remaining_length(&packet).unwrap();
+                assert_eq!(remaining_length, packet.len() - 1 - size);
+                // The MQTT 3.1.1 decoder rejects these
+                if matches!(packet_type, PacketType::AUTH | PacketType::RESERVED) {
+                    continue;
+                }
+                let mut reader = Cursor::new(&packet);
+                let decoded = VariablePacket::decode(&mut reader);
+                assert!(decoded.is_ok(), "{packet:?}: {decoded:?}");
+                assert_eq!(reader.position() as usize, packet.len());
+            }
+        }
     }
 
-    #[test]
-    fn generate_pingreq_packet() {
-        let pingreq = mqtt::packet::PingreqPacket::new();
-        let mut packet = Vec::new();
-        pingreq.encode(&mut packet).unwrap();
-        println!("{packet:?}");
+    #[tokio::test]
+    async fn test_exchange() {
+        let (mut client, mut broker) = tokio::io::duplex(1024);
+        tokio::spawn(async move {
+            let mut buf = [0; 16];
+            // A SUBACK in two pieces and a PUBLISH in response to the SUBSCRIBE
+            let _ = broker.read(&mut buf).await;
+            broker.write_all(&[0x90, 3, 0]).await.unwrap();
+            tokio::time::sleep(Duration::from_millis(5)).await;
+            broker
+                .write_all(&[1, 0, 0x30, 3, 0, 1, b'a'])
+                .await
+                .unwrap();
+            // Nothing for the PUBLISH and the connection is closed after the DISCONNECT
+            let _ = broker.read(&mut buf).await;
+            let _ = broker.read(&mut buf).await;
+        });
+        let mut packets = Packets::new();
+        packets.append(&[0x82, 6, 0, 1, 0, 1, b'a', 0]);
+        // An emptied packet isn't sent, but keeps its place in the responses
+        packets.append(&[]);
+        packets.append(&[0x30, 3, 0, 1, b'a']);
+        packets.append(&[0xE0, 0]);
+        packets.append(&[0xC0, 0]);
+        let exchange = send_packets(&mut client, &packets, Novelty::Signature, 50).await;
+        assert_eq!(
+            exchange.responses,
+            vec![
+                vec![0x90, 3, 0, 1, 0, 0x30, 3, 0, 1, b'a'],
+                vec![],
+                vec![],
+                vec![]
+            ]
+        );
+        assert_eq!(exchange.closed, Some(SendError::ReceiveErr));
+        let packet_queue = Arc::new(RwLock::new(PacketQueue::default()));
+        assert!(new_behavior(&exchange, &packets, &packet_queue, &Feedback::default()).await);
+        assert!(!new_behavior(&exchange, &packets, &packet_queue, &Feedback::default()).await);
     }
 }
@@ -0,0 +1,261 @@
+//! Abstract signatures of broker responses. Responses echo packet identifiers, topics and payloads,
+//! so the raw bytes differ for every input. The signature only keeps what describes the behavior of
+//! the broker: the packet types and flags, reason and return codes and which properties were set.
+use super::decode::PUBLISH;
+use super::length::decode_remaining_length;
+use super::v5::property_ids;
+use clap::ValueEnum;
+
+const CONNACK: u8 = 2;
+const SUBACK: u8 = 9;
+const UNSUBACK: u8 = 11;
+const DISCONNECT: u8 = 14;
+const AUTH: u8 = 15;
+// Separates the signatures of the responses to two packets
+const NEXT_PACKET: u8 = 3;
+
+/// What makes a response a new behavior
+#[derive(ValueEnum, Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+pub enum Novelty {
+    /// The signature of the response
+    #[default]
+    Signature,
+    /// The raw bytes of the response
+    Raw,
+}
+
+impl Novelty {
+    /// The key of the responses to a chain in the packet queue. `responses` holds what the broker
+    /// sent after each packet, `closed` whether it closed the connection after the last one
+    pub fn fingerprint(&self, responses: &[Vec<u8>], closed: bool) -> Vec<u8> {
+        let mut key = Vec::new();
+        for response in responses {
+            match self {
+                Novelty::Signature => {
+                    for signature in signatures(response) {
+                        signature.encode(&mut key);
+                    }
+                    key.push(NEXT_PACKET);
+                }
+                Novelty::Raw => {
+                    key.extend((response.len() as u32).to_be_bytes());
+                    key.extend(response);
+                }
+            }
+        }
+        if closed {
+            match self {
+                Novelty::Signature => Signature::Closed.encode(&mut key),
+                // Shorter than a length, so it can't be mistaken for another response
+                Novelty::Raw => key.push(0xFF),
+            }
+        }
+        key
+    }
+
+    /// Whether the response ends with a complete packet. Raw responses are always complete, as
+    /// they don't need to be MQTT
+    pub fn is_complete(&self, response: &[u8]) -> bool {
+        match self {
+            Novelty::Signature => signatures(response).last() != Some(&Signature::Malformed),
+            Novelty::Raw => true,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum Signature {
+    Packet {
+        // Packet type and flags
+        first_byte: u8,
+        // Reason codes, return codes and the flags of a CONNACK
+        codes: Vec<u8>,
+        // The identifiers of the MQTT 5 properties, sorted
+        properties: Vec<u8>,
+    },
+    // Bytes which aren't a complete packet
+    Malformed,
+    // The broker closed the connection after this packet
+    Closed,
+}
+
+impl Signature {
+    /// Appends the signature to the key. Every field is prefixed with its length, so different
+    /// signatures never have the same encoding
+    pub fn encode(&self, key: &mut Vec<u8>) {
+        match self {
+            Signature::Packet {
+                first_byte,
+                codes,
+                properties,
+            } => {
+                key.extend([0, *first_byte]);
+                for field in [codes, properties] {
+                    // There are at most 255 distinct codes and properties
+                    key.push(field.len().min(u8::MAX as usize) as u8);
+                    key.extend(field.iter().take(u8::MAX as usize));
+                }
+            }
+            Signature::Malformed => key.push(1),
+            Signature::Closed => key.push(2),
+        }
+    }
+}
+
+/// Splits the response into packets by their Remaining Length and returns their signatures
+pub fn signatures(response: &[u8]) -> Vec<Signature> {
+    let mut signatures = Vec::new();
+    let mut rest = response;
+    while !rest.is_empty() {
+        let Some((remaining_length, size)) = decode_remaining_length(rest) else {
+            signatures.push(Signature::Malformed);
+            break;
+        };
+        let end = 1 + size + remaining_length;
+        if end > rest.len() {
+            signatures.push(Signature::Malformed);
+            break;
+        }
+        signatures.push(signature(&rest[..end], 1 + size));
+        rest = &rest[end..];
+    }
+    signatures
+}
+
+/// The signature of a single packet whose variable header starts at `start`
+fn signature(packet: &[u8], start: usize) -> Signature {
+    let body = &packet[start..];
+    // The properties of forwarded PUBLISHes are set by the publisher, not the broker
+    let (property_range, properties) = match packet[0] >> 4 {
+        PUBLISH => (None, None),
+        _ => property_ids(packet).unzip(),
+    };
+    let mut codes = match packet[0] >> 4 {
+        // Session present flag and return code
+        CONNACK => body.iter().take(2).copied().collect(),
+        // The packet identifier is followed by the reason code, if there is one
+        4..=7 => body.get(2).into_iter().copied().collect(),
+        // The codes follow the properties in MQTT 5 and the packet identifier in MQTT 3.1.1. Empty
+        // property lists of MQTT 5 can't be told from a return code of 0, so they are kept
+        SUBACK | UNSUBACK => match &property_range {
+            Some(range) if !range.is_empty() => packet[range.end..].to_vec(),
+            _ => body.get(2..).unwrap_or_default().to_vec(),
+        },
+        DISCONNECT | AUTH => body.first().into_iter().copied().collect(),
+        // Topic and payload of a PUBLISH are left out, QoS, DUP and RETAIN are part of the flags
+        _ => Vec::new(),
+    };
+    if matches!(packet[0] >> 4, SUBACK | UNSUBACK) {
+        // One code per topic, how many topics there were doesn't matter
+        codes.sort_unstable();
+        codes.dedup();
+    }
+    let mut properties = properties.unwrap_or_default();
+    properties.sort_unstable();
+    properties.dedup();
+    Signature::Packet {
+        first_byte: packet[0],
+        codes,
+        properties,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn key(novelty: Novelty, response: &[u8]) -> Vec<u8> {
+        novelty.fingerprint(&[response.to_vec()], false)
+    }
+
+    #[test]
+    fn test_signatures_ignore_identifiers_and_payloads() {
+        let novelty = Novelty::Signature;
+        // PUBACKs for different packet identifiers
+        assert_eq!(
+            key(novelty, &[0x40, 2, 0, 1]),
+            key(novelty, &[0x40, 2, 0, 2])
+        );
+        assert_ne!(
+            key(Novelty::Raw, &[0x40, 2, 0, 1]),
+            key(Novelty::Raw, &[0x40, 2, 0, 2])
+        );
+        // Forwarded PUBLISHes with different topics and payloads, but the same QoS
+        assert_eq!(
+            key(novelty, &[0x32, 6, 0, 1, b'a', 0, 1, 7]),
+            key(novelty, &[0x32, 7, 0, 2, b'b', b'c', 0, 9, 8])
+        );
+        assert_ne!(
+            key(novelty, &[0x30, 4, 0, 1, b'a', 7]),
+            key(novelty, &[0x32, 6, 0, 1, b'a', 0, 1, 7])
+        );
+        // SUBACKs with a different number of topics
+        assert_eq!(
+            key(novelty, &[0x90, 3, 0, 1, 0]),
+            key(novelty, &[0x90, 5, 0, 9, 0, 0, 0])
+        );
+        // MQTT 3.1.1 SUBACKs which look like they have a property list
+        assert_ne!(
+            key(novelty, &[0x90, 4, 0, 1, 1, 2]),
+            key(novelty, &[0x90, 4, 0, 1, 1, 0x80])
+        );
+        // CONNACKs with different return codes
+        assert_ne!(
+            key(novelty, &[0x20, 2, 0, 0]),
+            key(novelty, &[0x20, 2, 0, 5])
+        );
+    }
+
+    #[test]
+    fn test_signatures() {
+        // CONNACK with a reason string and a user property, followed by a truncated packet
+        let response = [
+            0x20, 13, 0, 0x87, 10, 38, 0, 1, b'k', 0, 1, b'v', 31, 0, 0, 0x30,
+        ];
+        assert_eq!(
+            signatures(&response),
+            vec![
+                Signature::Packet {
+                    first_byte: 0x20,
+                    codes: vec![0, 0x87],
+                    properties: vec![31, 38],
+                },
+                Signature::Malformed
+            ]
+        );
+        assert!(signatures(&[]).is_empty());
+        assert!(!Novelty::Signature.is_complete(&response));
+        assert!(Novelty::Signature.is_complete(&response[..15]));
+        assert_ne!(
+            key(Novelty::Signature, &[0xD0, 0]),
+            key(Novelty::Signature, &[0xD0, 0, 0xD0, 0])
+        );
+    }
+
+    #[test]
+    fn test_fingerprint() {
+        let pingresp = vec![0xD0, 0];
+        let suback = vec![0x90, 3, 0, 1, 0];
+        for novelty in [Novelty::Signature, Novelty::Raw] {
+            // The order of the responses matters
+            assert_ne!(
+                novelty.fingerprint(&[pingresp.clone(), suback.clone()], false),
+                novelty.fingerprint(&[suback.clone(), pingresp.clone()], false)
+            );
+            // So does the packet without a response
+            assert_ne!(
+                novelty.fingerprint(&[vec![], pingresp.clone()], false),
+                novelty.fingerprint(&[pingresp.clone(), vec![]], false)
+            );
+            // And after which packet the connection was closed
+            assert_ne!(
+                novelty.fingerprint(std::slice::from_ref(&pingresp), true),
+                novelty.fingerprint(&[pingresp.clone(), vec![]], true)
+            );
+            assert_ne!(
+                novelty.fingerprint(std::slice::from_ref(&pingresp), true),
+                novelty.fingerprint(std::slice::from_ref(&pingresp), false)
+            );
+        }
+    }
+}
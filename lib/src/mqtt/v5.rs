@@ -0,0 +1,596 @@
+//! MQTT 5 packets. Unlike the MQTT 3.1.1 packets these are generated at runtime, so every packet
+//! gets its own random property list. The property lists can be mutated afterwards, since property
+//! parsing is where brokers have the most bugs.
+use super::decode::{CONNECT, PUBLISH, SUBSCRIBE, UNSUBSCRIBE};
+use super::length::{decode_variable_byte_integer, encode_remaining_length, fix_remaining_length};
+use super::{
+    finish_packet, generate_client_id, generate_keep_alive, generate_packet_id, generate_password,
+    generate_topic, push_string, random_bytes,
+};
+use crate::markov::PacketType;
+use rand::distributions::Standard;
+use rand::prelude::Distribution;
+use rand::seq::SliceRandom;
+use rand::Rng;
+use rand_xoshiro::Xoshiro256PlusPlus;
+use std::ops::Range;
+
+const CONNACK: u8 = 2;
+const DISCONNECT: u8 = 14;
+const AUTH: u8 = 15;
+
+const USER_PROPERTY: u8 = 38;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum PropertyType {
+    Byte,
+    TwoByteInteger,
+    FourByteInteger,
+    VariableByteInteger,
+    String,
+    BinaryData,
+    StringPair,
+}
+
+/// All MQTT 5 properties by their identifier
+const PROPERTIES: [(u8, PropertyType); 27] = [
+    // Payload Format Indicator
+    (1, PropertyType::Byte),
+    // Message Expiry Interval
+    (2, PropertyType::FourByteInteger),
+    // Content Type
+    (3, PropertyType::String),
+    // Response Topic
+    (8, PropertyType::String),
+    // Correlation Data
+    (9, PropertyType::BinaryData),
+    // Subscription Identifier
+    (11, PropertyType::VariableByteInteger),
+    // Session Expiry Interval
+    (17, PropertyType::FourByteInteger),
+    // Assigned Client Identifier
+    (18, PropertyType::String),
+    // Server Keep Alive
+    (19, PropertyType::TwoByteInteger),
+    // Authentication Method
+    (21, PropertyType::String),
+    // Authentication Data
+    (22, PropertyType::BinaryData),
+    // Request Problem Information
+    (23, PropertyType::Byte),
+    // Will Delay Interval
+    (24, PropertyType::FourByteInteger),
+    // Request Response Information
+    (25, PropertyType::Byte),
+    // Response Information
+    (26, PropertyType::String),
+    // Server Reference
+    (28, PropertyType::String),
+    // Reason String
+    (31, PropertyType::String),
+    // Receive Maximum
+    (33, PropertyType::TwoByteInteger),
+    // Topic Alias Maximum
+    (34, PropertyType::TwoByteInteger),
+    // Topic Alias
+    (35, PropertyType::TwoByteInteger),
+    // Maximum QoS
+    (36, PropertyType::Byte),
+    // Retain Available
+    (37, PropertyType::Byte),
+    // User Property
+    (USER_PROPERTY, PropertyType::StringPair),
+    // Maximum Packet Size
+    (39, PropertyType::FourByteInteger),
+    // Wildcard Subscription Available
+    (40, PropertyType::Byte),
+    // Subscription Identifier Available
+    (41, PropertyType::Byte),
+    // Shared Subscription Available
+    (42, PropertyType::Byte),
+];
+
+const CONNECT_PROPERTIES: [u8; 9] = [17, 21, 22, 23, 25, 33, 34, USER_PROPERTY, 39];
+const WILL_PROPERTIES: [u8; 7] = [1, 2, 3, 8, 9, 24, USER_PROPERTY];
+const PUBLISH_PROPERTIES: [u8; 8] = [1, 2, 3, 8, 9, 11, 35, USER_PROPERTY];
+const SUBSCRIBE_PROPERTIES: [u8; 2] = [11, USER_PROPERTY];
+const UNSUBSCRIBE_PROPERTIES: [u8; 1] = [USER_PROPERTY];
+const DISCONNECT_PROPERTIES: [u8; 4] = [17, 28, 31, USER_PROPERTY];
+const AUTH_PROPERTIES: [u8; 4] = [21, 22, 31, USER_PROPERTY];
+const ACK_PROPERTIES: [u8; 2] = [31, USER_PROPERTY];
+const CONNACK_PROPERTIES: [u8; 17] = [
+    17,
+    18,
+    19,
+    21,
+    22,
+    26,
+    28,
+    31,
+    33,
+    34,
+    36,
+    37,
+    USER_PROPERTY,
+    39,
+    40,
+    41,
+    42,
+];
+
+const STRINGS: [&str; 6] = [
+    "",
+    "topic",
+    "a/b",
+    "text/plain",
+    "SCRAM-SHA-1",
+    "rusty-FUME",
+];
+
+fn property_type(id: u8) -> Option<PropertyType> {
+    PROPERTIES
+        .iter()
+        .find(|(property, _)| *property == id)
+        .map(|(_, property_type)| *property_type)
+}
+
+fn random_string(rng: &mut Xoshiro256PlusPlus) -> Vec<u8> {
+    if rng.gen_bool(0.8) {
+        STRINGS.choose(rng).unwrap().as_bytes().to_vec()
+    } else {
+        (0..rng.gen_range(0..64))
+            .map(|_| rng.gen_range(b' '..=b'~'))
+            .collect()
+    }
+}
+
+/// A property with a random value of the right type. Unknown identifiers get a random byte
+fn generate_property(id: u8, rng: &mut Xoshiro256PlusPlus) -> Vec<u8> {
+    let mut property = vec![id];
+    match property_type(id) {
+        // Most byte properties are booleans
+        Some(PropertyType::Byte) => property.push(rng.gen_range(0..=2)),
+        Some(PropertyType::TwoByteInteger) => {
+            property.extend_from_slice(&rng.gen::<u16>().to_be_bytes())
+        }
+        Some(PropertyType::FourByteInteger) => {
+            property.extend_from_slice(&rng.gen::<u32>().to_be_bytes())
+        }
+        Some(PropertyType::VariableByteInteger) => {
+            property.extend(encode_remaining_length(rng.gen_range(0..=268_435_455)))
+        }
+        Some(PropertyType::String) => push_string(&mut property, &random_string(rng)),
+        Some(PropertyType::BinaryData) => {
+            let mut data = vec![0; rng.gen_range(0..32)];
+            rng.fill(&mut data[..]);
+            push_string(&mut property, &data);
+        }
+        Some(PropertyType::StringPair) => {
+            push_string(&mut property, &random_string(rng));
+            push_string(&mut property, &random_string(rng));
+        }
+        None => property.push(rng.gen()),
+    }
+    property
+}
+
+/// A property list with up to four random properties allowed in the packet, prefixed by its length.
+/// Only user properties may appear more than once.
+fn generate_properties(allowed: &[u8], rng: &mut Xoshiro256PlusPlus) -> Vec<u8> {
+    let count = rng.gen_range(0..=4);
+    let mut ids: Vec<u8> = allowed
+        .choose_multiple(rng, count.min(allowed.len()))
+        .copied()
+        .collect();
+    while ids.len() < count && allowed.contains(&USER_PROPERTY) {
+        ids.push(USER_PROPERTY);
+    }
+    let properties: Vec<u8> = ids
+        .into_iter()
+        .flat_map(|id| generate_property(id, rng))
+        .collect();
+    let mut list = encode_remaining_length(properties.len());
+    list.extend(properties);
+    list
+}
+
+fn generate_connect_packet(rng: &mut Xoshiro256PlusPlus) -> Vec<u8> {
+    let client_id = generate_client_id(rng);
+    let will = rng.gen_bool(0.5);
+    let username = rng.gen_bool(0.3);
+    // Unlike MQTT 3.1.1, a password is allowed without a username
+    let password = rng.gen_bool(0.3);
+    let mut flags = 0;
+    // The broker assigns an id to empty client ids, with or without a clean start
+    if rng.gen_bool(0.5) {
+        flags |= 0b10;
+    }
+    if will {
+        flags |= 0b100 | rng.gen_range(0..=2) << 3;
+        if rng.gen_bool(0.5) {
+            flags |= 0b10_0000;
+        }
+    }
+    if username {
+        flags |= 0b1000_0000;
+    }
+    if password {
+        flags |= 0b100_0000;
+    }
+    let mut body = Vec::new();
+    push_string(&mut body, b"MQTT");
+    body.push(5);
+    body.push(flags);
+    body.extend_from_slice(&generate_keep_alive(rng).to_be_bytes());
+    body.extend(generate_properties(&CONNECT_PROPERTIES, rng));
+    push_string(&mut body, &client_id);
+    if will {
+        body.extend(generate_properties(&WILL_PROPERTIES, rng));
+        push_string(&mut body, &generate_topic(rng, false));
+        push_string(&mut body, &random_bytes(rng, 32));
+    }
+    if username {
+        push_string(&mut body, b"user");
+    }
+    if password {
+        push_string(&mut body, &generate_password(rng));
+    }
+    finish_packet(CONNECT << 4, body)
+}
+
+fn generate_publish_packet(rng: &mut Xoshiro256PlusPlus) -> Vec<u8> {
+    let qos: u8 = rng.gen_range(0..=2);
+    let mut first_byte = PUBLISH << 4 | qos << 1 | u8::from(rng.gen_bool(0.2));
+    // DUP may only be set for QoS > 0
+    if qos > 0 && rng.gen_bool(0.2) {
+        first_byte |= 0b1000;
+    }
+    let mut body = Vec::new();
+    push_string(&mut body, &generate_topic(rng, false));
+    if qos > 0 {
+        body.extend_from_slice(&rng.gen_range(1..=u16::MAX).to_be_bytes());
+    }
+    body.extend(generate_properties(&PUBLISH_PROPERTIES, rng));
+    body.extend(random_bytes(rng, 64));
+    finish_packet(first_byte, body)
+}
+
+fn generate_subscribe_packet(rng: &mut Xoshiro256PlusPlus) -> Vec<u8> {
+    let mut body = rng.gen_range(1..=u16::MAX).to_be_bytes().to_vec();
+    body.extend(generate_properties(&SUBSCRIBE_PROPERTIES, rng));
+    for _ in 0..rng.gen_range(1..=3) {
+        push_string(&mut body, &generate_topic(rng, true));
+        // QoS, No Local, Retain As Published and Retain Handling
+        let options = rng.gen_range(0..=2) | rng.gen_range(0..=1) << 2 | rng.gen_range(0..=1) << 3;
+        body.push(options | rng.gen_range(0..=2) << 4);
+    }
+    finish_packet(SUBSCRIBE << 4 | 0b10, body)
+}
+
+fn generate_unsubscribe_packet(rng: &mut Xoshiro256PlusPlus) -> Vec<u8> {
+    let mut body = rng.gen_range(1..=u16::MAX).to_be_bytes().to_vec();
+    body.extend(generate_properties(&UNSUBSCRIBE_PROPERTIES, rng));
+    for _ in 0..rng.gen_range(1..=3) {
+        push_string(&mut body, &generate_topic(rng, true));
+    }
+    finish_packet(UNSUBSCRIBE << 4 | 0b10, body)
+}
+
+fn generate_disconnect_packet(rng: &mut Xoshiro256PlusPlus) -> Vec<u8> {
+    // Normal disconnection, disconnect with will message and unspecified error
+    let mut body = vec![*[0x00, 0x04, 0x80].choose(rng).unwrap()];
+    body.extend(generate_properties(&DISCONNECT_PROPERTIES, rng));
+    finish_packet(DISCONNECT << 4, body)
+}
+
+fn generate_auth_packet(rng: &mut Xoshiro256PlusPlus) -> Vec<u8> {
+    // Success, continue authentication and re-authenticate
+    let mut body = vec![*[0x00, 0x18, 0x19].choose(rng).unwrap()];
+    body.extend(generate_properties(&AUTH_PROPERTIES, rng));
+    finish_packet(AUTH << 4, body)
+}
+
+/// PUBACK, PUBREC, PUBREL and PUBCOMP with a reason code and properties
+fn generate_ack_packet(first_byte: u8, rng: &mut Xoshiro256PlusPlus) -> Vec<u8> {
+    let mut body = generate_packet_id(rng).to_vec();
+    // Success, no matching subscribers, packet identifier not found and unspecified error
+    body.push(*[0x00, 0x10, 0x92, 0x80].choose(rng).unwrap());
+    body.extend(generate_properties(&ACK_PROPERTIES, rng));
+    finish_packet(first_byte, body)
+}
+
+fn generate_connack_packet(rng: &mut Xoshiro256PlusPlus) -> Vec<u8> {
+    // Session present and success, unspecified error or not authorized
+    let mut body = vec![
+        rng.gen_range(0..=1),
+        *[0x00, 0x80, 0x87].choose(rng).unwrap(),
+    ];
+    body.extend(generate_properties(&CONNACK_PROPERTIES, rng));
+    finish_packet(CONNACK << 4, body)
+}
+
+/// SUBACK and UNSUBACK with a reason code for each topic filter
+fn generate_suback_packet(first_byte: u8, rng: &mut Xoshiro256PlusPlus) -> Vec<u8> {
+    let mut body = generate_packet_id(rng).to_vec();
+    body.extend(generate_properties(&ACK_PROPERTIES, rng));
+    for _ in 0..rng.gen_range(1..=4) {
+        body.push(*[0x00, 0x01, 0x02, 0x11, 0x80, 0x87].choose(rng).unwrap());
+    }
+    finish_packet(first_byte, body)
+}
+
+/// Generates a packet of the type, including packets only the broker should send
+pub(crate) fn generate_packet(packet_type: &PacketType, rng: &mut Xoshiro256PlusPlus) -> Vec<u8> {
+    match packet_type {
+        PacketType::AUTH => generate_auth_packet(rng),
+        PacketType::CONNACK => generate_connack_packet(rng),
+        PacketType::CONNECT => generate_connect_packet(rng),
+        PacketType::DISCONNECT => generate_disconnect_packet(rng),
+        PacketType::PINGREQ => vec![0xC0, 0],
+        PacketType::PINGRESP => vec![0xD0, 0],
+        PacketType::PUBACK => generate_ack_packet(0x40, rng),
+        PacketType::PUBCOMP => generate_ack_packet(0x70, rng),
+        PacketType::PUBLISH => generate_publish_packet(rng),
+        PacketType::PUBREC => generate_ack_packet(0x50, rng),
+        PacketType::PUBREL => generate_ack_packet(0x62, rng),
+        // AUTH uses 15 in MQTT 5, so only 0 is reserved
+        PacketType::RESERVED => finish_packet(rng.gen_range(0..16), Vec::new()),
+        PacketType::SUBACK => generate_suback_packet(0x90, rng),
+        PacketType::SUBSCRIBE => generate_subscribe_packet(rng),
+        PacketType::UNSUBACK => generate_suback_packet(0xB0, rng),
+        PacketType::UNSUBSCRIBE => generate_unsubscribe_packet(rng),
+    }
+}
+
+/// Finds the property list of the variable header. Returns the offset of its length and the range
+/// of the properties.
+fn property_list(packet: &[u8]) -> Option<(usize, Range<usize>)> {
+    let (remaining_length, size) = decode_variable_byte_integer(packet.get(1..)?)?;
+    let start = 1 + size;
+    let string_end = |offset: usize| -> Option<usize> {
+        let prefix = packet.get(offset..offset + 2)?;
+        Some(offset + 2 + u16::from_be_bytes([prefix[0], prefix[1]]) as usize)
+    };
+    let offset = match packet[0] >> 4 {
+        // Protocol name, version, flags and keep alive. MQTT 3.1.1 CONNECTs have no properties
+        CONNECT => {
+            let name_end = string_end(start)?;
+            if packet.get(name_end) != Some(&5) {
+                return None;
+            }
+            name_end + 4
+        }
+        // Reason code and flags
+        CONNACK => start + 2,
+        PUBLISH if packet[0] & 0b110 != 0 => string_end(start)? + 2,
+        PUBLISH => string_end(start)?,
+        // Packet identifier and reason code, the properties may be omitted
+        4..=7 if remaining_length >= 4 => start + 3,
+        // Packet identifier
+        SUBSCRIBE | 9 | UNSUBSCRIBE | 11 => start + 2,
+        // Reason code, the properties may be omitted
+        DISCONNECT | AUTH if remaining_length >= 2 => start + 1,
+        _ => return None,
+    };
+    let (length, size) = decode_variable_byte_integer(packet.get(offset..)?)?;
+    let properties = offset + size..offset + size + length;
+    if properties.end > packet.len() {
+        return None;
+    }
+    Some((offset, properties))
+}
+
+/// The range of the property list of the packet and the identifiers of its properties. Lists with
+/// unknown or truncated properties are most likely no property list at all, so they are ignored
+pub(crate) fn property_ids(packet: &[u8]) -> Option<(Range<usize>, Vec<u8>)> {
+    let (_, properties) = property_list(packet)?;
+    let split = split_properties(packet, properties.clone());
+    let end = split
+        .last()
+        .map_or(properties.start, |property| property.end);
+    if end != properties.end {
+        return None;
+    }
+    let ids = split
+        .iter()
+        .map(|property| packet[property.start])
+        .collect();
+    Some((properties, ids))
+}
+
+/// Splits the property list into the single properties. Parsing stops at the first unknown or
+/// truncated property.
+fn split_properties(packet: &[u8], properties: Range<usize>) -> Vec<Range<usize>> {
+    let mut ranges = Vec::new();
+    let mut offset = properties.start;
+    let string_length = |offset: usize| -> Option<usize> {
+        let prefix = packet.get(offset..offset + 2)?;
+        Some(2 + u16::from_be_bytes([prefix[0], prefix[1]]) as usize)
+    };
+    while offset < properties.end {
+        let Some(property_type) = property_type(packet[offset]) else {
+            break;
+        };
+        let value = offset + 1;
+        let size = match property_type {
+            PropertyType::Byte => Some(1),
+            PropertyType::TwoByteInteger => Some(2),
+            PropertyType::FourByteInteger => Some(4),
+            PropertyType::VariableByteInteger => {
+                decode_variable_byte_integer(&packet[value..properties.end]).map(|(_, size)| size)
+            }
+            PropertyType::String | PropertyType::BinaryData => string_length(value),
+            PropertyType::StringPair => {
+                string_length(value).and_then(|key| Some(key + string_length(value + key)?))
+            }
+        };
+        match size {
+            Some(size) if value + size <= properties.end => {
+                ranges.push(offset..value + size);
+                offset = value + size;
+            }
+            _ => break,
+        }
+    }
+    ranges
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum PropertyMutation {
+    // Adds a random property, which may not be allowed in the packet or not exist at all
+    Add,
+    // Repeats a property, which is only allowed for user properties
+    Duplicate,
+    // Removes a property, e.g. the authentication method while keeping the data
+    Remove,
+    // Breaks the value or identifier of a property
+    Corrupt,
+}
+
+impl Distribution<PropertyMutation> for Standard {
+    fn sample<R: Rng + ?Sized>(&self, rng: &mut R) -> PropertyMutation {
+        match rng.gen_range(0..4) {
+            0 => PropertyMutation::Add,
+            1 => PropertyMutation::Duplicate,
+            2 => PropertyMutation::Remove,
+            3 => PropertyMutation::Corrupt,
+            _ => unreachable!(),
+        }
+    }
+}
+
+/// Mutates the property list of the packet and updates the property and remaining length. Returns
+/// false if the packet has no property list.
+pub(crate) fn mutate_properties(
+    packet: &mut Vec<u8>,
+    mutation: &PropertyMutation,
+    rng: &mut Xoshiro256PlusPlus,
+) -> bool {
+    let Some((length_offset, properties)) = property_list(packet) else {
+        return false;
+    };
+    let mut list = packet[properties.clone()].to_vec();
+    let ranges: Vec<Range<usize>> = split_properties(packet, properties.clone())
+        .into_iter()
+        .map(|range| range.start - properties.start..range.end - properties.start)
+        .collect();
+    match (mutation, ranges.choose(rng).cloned()) {
+        (PropertyMutation::Add, _) | (_, None) => {
+            let id = if rng.gen_bool(0.9) {
+                PROPERTIES.choose(rng).unwrap().0
+            } else {
+                rng.gen()
+            };
+            let idx = ranges
+                .iter()
+                .map(|range| range.end)
+                .chain([0])
+                .collect::<Vec<_>>()
+                .choose(rng)
+                .copied()
+                .unwrap();
+            list.splice(idx..idx, generate_property(id, rng));
+        }
+        (PropertyMutation::Duplicate, Some(range)) => {
+            let property = list[range.clone()].to_vec();
+            let copies = if rng.gen_bool(0.1) {
+                rng.gen_range(2..100)
+            } else {
+                1
+            };
+            list.splice(range.end..range.end, property.repeat(copies));
+        }
+        (PropertyMutation::Remove, Some(range)) => {
+            list.drain(range);
+        }
+        (PropertyMutation::Corrupt, Some(range)) => corrupt_property(&mut list, range, rng),
+    }
+    packet.splice(
+        length_offset..properties.end,
+        encode_remaining_length(list.len()).into_iter().chain(list),
+    );
+    fix_remaining_length(packet);
+    true
+}
+
+fn corrupt_property(list: &mut Vec<u8>, property: Range<usize>, rng: &mut Xoshiro256PlusPlus) {
+    let value = property.start + 1..property.end;
+    match rng.gen_range(0..5) {
+        // Another identifier, so the value is parsed as the wrong type
+        0 => list[property.start] = rng.gen(),
+        // Truncate the value
+        1 if !value.is_empty() => {
+            let length = rng.gen_range(0..value.len());
+            list.drain(value.start + length..value.end);
+        }
+        // Zero is forbidden for e.g. Receive Maximum, Topic Alias and Subscription Identifier
+        2 => list[value].fill(0),
+        3 => list[value].fill(0xFF),
+        // Lie about the length of strings and binary data
+        _ if matches!(
+            property_type(list[property.start]),
+            Some(PropertyType::String | PropertyType::BinaryData | PropertyType::StringPair)
+        ) =>
+        {
+            let length: u16 = rng.gen();
+            list[value.start..value.start + 2].copy_from_slice(&length.to_be_bytes());
+        }
+        // Overlong variable byte integers
+        _ if property_type(list[property.start]) == Some(PropertyType::VariableByteInteger) => {
+            list.splice(value, [0x80, 0x80, 0x80, 0x80, 0x01]);
+        }
+        _ => list[property.start] = 0,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mqtt::length::decode_remaining_length;
+    use crate::mqtt::tests::PACKET_TYPES;
+    use rand::SeedableRng;
+
+    #[test]
+    fn test_generated_properties() {
+        let mut rng = Xoshiro256PlusPlus::seed_from_u64(0);
+        for _ in 0..100 {
+            for packet_type in PACKET_TYPES.iter() {
+                let packet = generate_packet(packet_type, &mut rng);
+                let (remaining_length, size) = decode_remaining_length(&packet).unwrap();
+                assert_eq!(remaining_length, packet.len() - 1 - size);
+                if matches!(
+                    packet_type,
+                    PacketType::PINGREQ | PacketType::PINGRESP | PacketType::RESERVED
+                ) {
+                    continue;
+                }
+                // Every generated property can be parsed again
+                let (_, properties) = property_list(&packet).unwrap();
+                let ranges = split_properties(&packet, properties.clone());
+                let end = ranges.last().map_or(properties.start, |range| range.end);
+                assert_eq!(end, properties.end, "{packet_type:?} {packet:?}");
+            }
+        }
+    }
+
+    #[test]
+    fn test_mutate_properties() {
+        let mut rng = Xoshiro256PlusPlus::seed_from_u64(0);
+        for _ in 0..100 {
+            let mut packet = generate_packet(&PacketType::CONNECT, &mut rng);
+            let mutation = rng.gen();
+            assert!(mutate_properties(&mut packet, &mutation, &mut rng));
+            let (remaining_length, size) = decode_remaining_length(&packet).unwrap();
+            assert_eq!(remaining_length, packet.len() - 1 - size);
+            // The property length still covers the list
+            assert!(property_list(&packet).is_some());
+        }
+        // MQTT 3.1.1 CONNECTs have no properties
+        let mut packet = crate::mqtt::generate_connect_packet(&mut rng);
+        assert!(!mutate_properties(
+            &mut packet,
+            &PropertyMutation::Add,
+            &mut rng
+        ));
+    }
+}
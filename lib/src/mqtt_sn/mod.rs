@@ -0,0 +1,171 @@
+//! MQTT-SN packets, as spoken by MQTT-SN gateways over UDP. Every packet starts with its length
+//! followed by the message type.
+use crate::markov::ByteStream;
+use rand::distributions::Standard;
+use rand::prelude::Distribution;
+use rand::Rng;
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::time::timeout;
+use tracing::debug;
+
+/// The message type of a CONNECT
+pub(crate) const CONNECT: u8 = 0x04;
+
+/// The message type of the packet. Packets longer than 255 bytes start with 0x01 and a two byte
+/// length
+pub(crate) fn message_type(packet: &[u8]) -> Option<u8> {
+    match packet.first()? {
+        1 => packet.get(3).copied(),
+        _ => packet.get(1).copied(),
+    }
+}
+
+/// The MQTT-SN Packet types
+#[allow(clippy::upper_case_acronyms)]
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum SnPacketType {
+    ADVERTISE,
+    SEARCHGW,
+    GWINFO,
+    CONNECT,
+    CONNACK,
+    WILLTOPICREQ,
+    WILLTOPIC,
+    WILLMSGREQ,
+    WILLMSG,
+    REGISTER,
+    REGACK,
+    PUBLISH,
+    PUBACK,
+    PUBCOMP,
+    PUBREC,
+    PUBREL,
+    SUBSCRIBE,
+    SUBACK,
+    UNSUBSCRIBE,
+    UNSUBACK,
+    PINGREQ,
+    PINGRESP,
+    DISCONNECT,
+    WILLTOPICUPD,
+    WILLTOPICRESP,
+    WILLMSGUPD,
+    WILLMSGRESP,
+    ENCAPSULATED,
+}
+
+// Gateway to client packets are sampled as well, gateways should survive them
+impl Distribution<SnPacketType> for Standard {
+    fn sample<R: Rng + ?Sized>(&self, rng: &mut R) -> SnPacketType {
+        match rng.gen_range(0..28) {
+            0 => SnPacketType::ADVERTISE,
+            1 => SnPacketType::SEARCHGW,
+            2 => SnPacketType::GWINFO,
+            3 => SnPacketType::CONNECT,
+            4 => SnPacketType::CONNACK,
+            5 => SnPacketType::WILLTOPICREQ,
+            6 => SnPacketType::WILLTOPIC,
+            7 => SnPacketType::WILLMSGREQ,
+            8 => SnPacketType::WILLMSG,
+            9 => SnPacketType::REGISTER,
+            10 => SnPacketType::REGACK,
+            11 => SnPacketType::PUBLISH,
+            12 => SnPacketType::PUBACK,
+            13 => SnPacketType::PUBCOMP,
+            14 => SnPacketType::PUBREC,
+            15 => SnPacketType::PUBREL,
+            16 => SnPacketType::SUBSCRIBE,
+            17 => SnPacketType::SUBACK,
+            18 => SnPacketType::UNSUBSCRIBE,
+            19 => SnPacketType::UNSUBACK,
+            20 => SnPacketType::PINGREQ,
+            21 => SnPacketType::PINGRESP,
+            22 => SnPacketType::DISCONNECT,
+            23 => SnPacketType::WILLTOPICUPD,
+            24 => SnPacketType::WILLTOPICRESP,
+            25 => SnPacketType::WILLMSGUPD,
+            26 => SnPacketType::WILLMSGRESP,
+            27 => SnPacketType::ENCAPSULATED,
+            _ => unreachable!(),
+        }
+    }
+}
+
+/// A CONNECT with clean session, protocol id 1, a keepalive of 60s and the client id "rusty-FUME"
+pub(crate) fn generate_connect_packet() -> [u8; 16] {
+    [
+        16, 4, 4, 1, 0, 60, 114, 117, 115, 116, 121, 45, 70, 85, 77, 69,
+    ]
+}
+
+pub(crate) fn generate_packet(packet_type: &SnPacketType) -> Vec<u8> {
+    match packet_type {
+        // Gateway id 1, duration 900s
+        SnPacketType::ADVERTISE => vec![5, 0, 1, 3, 132],
+        // Radius 1
+        SnPacketType::SEARCHGW => vec![3, 1, 1],
+        SnPacketType::GWINFO => vec![3, 2, 1],
+        SnPacketType::CONNECT => generate_connect_packet().to_vec(),
+        SnPacketType::CONNACK => vec![3, 5, 0],
+        SnPacketType::WILLTOPICREQ => vec![2, 6],
+        // QoS 0 will on "topic"
+        SnPacketType::WILLTOPIC => vec![8, 7, 0, 116, 111, 112, 105, 99],
+        SnPacketType::WILLMSGREQ => vec![2, 8],
+        SnPacketType::WILLMSG => vec![6, 9, 119, 105, 108, 108],
+        // Register "topic" with message id 1
+        SnPacketType::REGISTER => vec![11, 10, 0, 0, 0, 1, 116, 111, 112, 105, 99],
+        SnPacketType::REGACK => vec![7, 11, 0, 1, 0, 1, 0],
+        // QoS 0 publish of "hello" on the short topic "to", so no REGISTER is needed
+        SnPacketType::PUBLISH => vec![12, 12, 2, 116, 111, 0, 0, 104, 101, 108, 108, 111],
+        SnPacketType::PUBACK => vec![7, 13, 0, 1, 0, 1, 0],
+        SnPacketType::PUBCOMP => vec![4, 14, 0, 1],
+        SnPacketType::PUBREC => vec![4, 15, 0, 1],
+        SnPacketType::PUBREL => vec![4, 16, 0, 1],
+        // QoS 0 subscription to "topic" with message id 1
+        SnPacketType::SUBSCRIBE => vec![10, 18, 0, 0, 1, 116, 111, 112, 105, 99],
+        SnPacketType::SUBACK => vec![8, 19, 0, 0, 1, 0, 1, 0],
+        SnPacketType::UNSUBSCRIBE => vec![10, 20, 0, 0, 2, 116, 111, 112, 105, 99],
+        SnPacketType::UNSUBACK => vec![4, 21, 0, 2],
+        SnPacketType::PINGREQ => vec![2, 22],
+        SnPacketType::PINGRESP => vec![2, 23],
+        SnPacketType::DISCONNECT => vec![2, 24],
+        SnPacketType::WILLTOPICUPD => vec![8, 26, 0, 116, 111, 112, 105, 99],
+        SnPacketType::WILLTOPICRESP => vec![3, 27, 0],
+        SnPacketType::WILLMSGUPD => vec![6, 28, 119, 105, 108, 108],
+        SnPacketType::WILLMSGRESP => vec![3, 29, 0],
+        // A PINGREQ forwarded for wireless node 1
+        SnPacketType::ENCAPSULATED => vec![4, 254, 0, 1, 2, 22],
+    }
+}
+
+pub async fn test_connection(stream: &mut impl ByteStream) -> color_eyre::Result<()> {
+    stream
+        .write_all(generate_connect_packet().as_slice())
+        .await?;
+    let mut buf = [0; 1024];
+    let _ = timeout(Duration::from_secs(1), stream.read(&mut buf)).await;
+    debug!("Received Packet hex encoded: {:?}", hex::encode(buf));
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::SeedableRng;
+    use rand_xoshiro::Xoshiro256PlusPlus;
+
+    #[test]
+    fn test_length_fields() {
+        let mut rng = Xoshiro256PlusPlus::seed_from_u64(0);
+        for _ in 0..1000 {
+            let packet_type: SnPacketType = rng.gen();
+            let packet = generate_packet(&packet_type);
+            match packet_type {
+                // The length of an encapsulated message only covers its own header
+                SnPacketType::ENCAPSULATED => assert_eq!(packet[0], 4),
+                _ => assert_eq!(packet[0] as usize, packet.len(), "{:?}", packet_type),
+            }
+        }
+    }
+}
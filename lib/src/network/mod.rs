@@ -0,0 +1,277 @@
+//! The transports used to talk to the broker. Which one is used is decided at runtime by the
+//! scheme of the target, e.g. `tcp://127.0.0.1:1883`, `tls://127.0.0.1:8883`,
+//! `ws://127.0.0.1:8080/mqtt`, `quic://127.0.0.1:14567`, `unix:///run/broker.sock` or `udp://127.0.0.1:1884` for MQTT-SN.
+//! Targets without a scheme are treated as plain TCP.
+use crate::markov::{ByteStream, Protocol};
+use color_eyre::eyre::bail;
+use color_eyre::Result;
+use futures::future::BoxFuture;
+use std::fmt::Display;
+use std::io;
+use std::str::FromStr;
+
+#[cfg(not(any(
+    feature = "tcp",
+    feature = "tls",
+    feature = "websocket",
+    feature = "quic",
+    feature = "udp",
+    all(unix, feature = "unix")
+)))]
+compile_error!("enable at least one transport feature: tcp, tls, websocket, quic, udp or unix");
+
+#[cfg(feature = "quic")]
+pub mod quic;
+#[cfg(any(feature = "tcp", feature = "tls", feature = "websocket"))]
+pub mod tcp;
+#[cfg(feature = "tls")]
+pub mod tls;
+#[cfg(feature = "udp")]
+mod udp;
+#[cfg(all(unix, feature = "unix"))]
+mod unix;
+#[cfg(feature = "websocket")]
+pub mod websocket;
+
+/// Raw access to the TLS layer of a connection, so the TLS implementation of the broker can be
+/// fuzzed as well
+pub trait TlsLayer: Send {
+    /// Writes the bytes directly to the socket, bypassing the TLS session
+    fn write_raw<'a>(&'a mut self, bytes: &'a [u8]) -> BoxFuture<'a, io::Result<()>>;
+    /// Sends a close_notify alert while keeping the connection open
+    fn close_notify(&mut self) -> BoxFuture<'_, io::Result<()>>;
+}
+
+/// The transport and address used to connect to the broker
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum Transport {
+    /// Plain TCP, `host:port`
+    #[cfg(feature = "tcp")]
+    Tcp(String),
+    /// TLS over TCP, `host:port`
+    #[cfg(feature = "tls")]
+    Tls(String),
+    /// The full `ws://` or `wss://` url
+    #[cfg(feature = "websocket")]
+    WebSocket(String),
+    /// QUIC, `host:port`
+    #[cfg(feature = "quic")]
+    Quic(String),
+    /// MQTT-SN over UDP, `host:port`
+    #[cfg(feature = "udp")]
+    Udp(String),
+    /// A Unix domain socket, the path of the socket
+    #[cfg(all(unix, feature = "unix"))]
+    Unix(String),
+}
+
+impl Transport {
+    /// The protocol spoken over this transport
+    pub fn protocol(&self) -> Protocol {
+        match self {
+            #[cfg(feature = "udp")]
+            Transport::Udp(_) => Protocol::MqttSn,
+            #[allow(unreachable_patterns)]
+            _ => Protocol::Mqtt,
+        }
+    }
+}
+
+impl FromStr for Transport {
+    type Err = color_eyre::Report;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let Some((scheme, address)) = s.split_once("://") else {
+            #[cfg(feature = "tcp")]
+            return Ok(Transport::Tcp(s.to_string()));
+            #[cfg(not(feature = "tcp"))]
+            bail!("Missing transport scheme in {s}");
+        };
+        match scheme {
+            #[cfg(feature = "tcp")]
+            "tcp" | "mqtt" => Ok(Transport::Tcp(address.to_string())),
+            #[cfg(feature = "tls")]
+            "tls" | "ssl" | "mqtts" => Ok(Transport::Tls(address.to_string())),
+            #[cfg(feature = "websocket")]
+            "ws" | "wss" => Ok(Transport::WebSocket(format!("{scheme}://{address}"))),
+            #[cfg(feature = "quic")]
+            "quic" => Ok(Transport::Quic(address.to_string())),
+            #[cfg(feature = "udp")]
+            "udp" | "mqtt-sn" => Ok(Transport::Udp(address.to_string())),
+            #[cfg(all(unix, feature = "unix"))]
+            "unix" => Ok(Transport::Unix(address.to_string())),
+            _ => bail!("Unsupported transport {scheme}, maybe it is not enabled as a feature?"),
+        }
+    }
+}
+
+impl Display for Transport {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            #[cfg(feature = "tcp")]
+            Transport::Tcp(address) => write!(f, "tcp://{address}"),
+            #[cfg(feature = "tls")]
+            Transport::Tls(address) => write!(f, "tls://{address}"),
+            #[cfg(feature = "websocket")]
+            Transport::WebSocket(url) => write!(f, "{url}"),
+            #[cfg(feature = "quic")]
+            Transport::Quic(address) => write!(f, "quic://{address}"),
+            #[cfg(feature = "udp")]
+            Transport::Udp(address) => write!(f, "udp://{address}"),
+            #[cfg(all(unix, feature = "unix"))]
+            Transport::Unix(path) => write!(f, "unix://{path}"),
+        }
+    }
+}
+
+/// The broker we connect to, together with the transport specific configuration
+#[derive(Debug, Clone)]
+pub struct Target {
+    pub transport: Transport,
+    /// The protocol spoken with the broker, by default the one of the transport
+    pub protocol: Protocol,
+    #[cfg(any(feature = "tcp", feature = "tls", feature = "websocket"))]
+    pub tcp: tcp::TcpConfig,
+    #[cfg(feature = "tls")]
+    pub tls: tls::TlsConfig,
+    #[cfg(feature = "quic")]
+    pub quic: quic::QuicConfig,
+}
+
+impl Target {
+    pub fn new(transport: Transport) -> Self {
+        Self {
+            protocol: transport.protocol(),
+            transport,
+            #[cfg(any(feature = "tcp", feature = "tls", feature = "websocket"))]
+            tcp: Default::default(),
+            #[cfg(feature = "tls")]
+            tls: Default::default(),
+            #[cfg(feature = "quic")]
+            quic: Default::default(),
+        }
+    }
+
+    #[cfg(any(feature = "tcp", feature = "tls", feature = "websocket"))]
+    pub fn with_tcp(mut self, tcp: tcp::TcpConfig) -> Self {
+        self.tcp = tcp;
+        self
+    }
+
+    pub fn with_protocol(mut self, protocol: Protocol) -> Self {
+        self.protocol = protocol;
+        self
+    }
+
+    #[cfg(feature = "tls")]
+    pub fn with_tls(mut self, tls: tls::TlsConfig) -> Self {
+        self.tls = tls;
+        self
+    }
+
+    #[cfg(feature = "quic")]
+    pub fn with_quic(mut self, quic: quic::QuicConfig) -> Self {
+        self.quic = quic;
+        self
+    }
+}
+
+impl Display for Target {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.transport)
+    }
+}
+
+/// Extracts the host of a `host:port` address, removing the brackets of IPv6 literals
+#[cfg(feature = "tls")]
+pub(crate) fn host_of(address: &str) -> &str {
+    if let Some(rest) = address.strip_prefix('[') {
+        return rest.split(']').next().unwrap_or(rest);
+    }
+    match address.rsplit_once(':') {
+        // More than one colon means this is an IPv6 literal without a port
+        Some((host, _)) if !host.contains(':') => host,
+        _ => address,
+    }
+}
+
+/// Connects to the broker with the transport of the target
+pub async fn connect_to_broker(target: &Target) -> Result<Box<dyn ByteStream>> {
+    match &target.transport {
+        #[cfg(feature = "tcp")]
+        Transport::Tcp(address) => Ok(Box::new(tcp::connect(address, &target.tcp).await?)),
+        #[cfg(feature = "tls")]
+        Transport::Tls(address) => {
+            let stream = tls::connect(address, &target.tcp, &target.tls).await?;
+            if target.tls.fuzz_tls_layer() {
+                Ok(Box::new(tls::TlsFuzzStream::new(stream)))
+            } else {
+                Ok(Box::new(stream))
+            }
+        }
+        #[cfg(feature = "websocket")]
+        Transport::WebSocket(url) => websocket::connect(url, target).await,
+        #[cfg(feature = "quic")]
+        Transport::Quic(address) => Ok(Box::new(quic::connect(address, &target.quic).await?)),
+        #[cfg(feature = "udp")]
+        Transport::Udp(address) => Ok(Box::new(udp::connect(address).await?)),
+        #[cfg(all(unix, feature = "unix"))]
+        Transport::Unix(path) => Ok(Box::new(unix::connect(path).await?)),
+    }
+}
+
+#[cfg(all(test, any(feature = "tcp", feature = "tls")))]
+mod tests {
+    use super::*;
+
+    #[cfg(feature = "tcp")]
+    #[test]
+    fn test_parse_transport() {
+        assert_eq!(
+            Transport::from_str("127.0.0.1:1883").unwrap(),
+            Transport::Tcp("127.0.0.1:1883".to_string())
+        );
+        assert_eq!(
+            Transport::from_str("tcp://127.0.0.1:1883").unwrap(),
+            Transport::Tcp("127.0.0.1:1883".to_string())
+        );
+        #[cfg(feature = "tls")]
+        assert_eq!(
+            Transport::from_str("tls://127.0.0.1:8883").unwrap(),
+            Transport::Tls("127.0.0.1:8883".to_string())
+        );
+        #[cfg(feature = "websocket")]
+        assert_eq!(
+            Transport::from_str("wss://127.0.0.1:8080/mqtt").unwrap(),
+            Transport::WebSocket("wss://127.0.0.1:8080/mqtt".to_string())
+        );
+        #[cfg(feature = "quic")]
+        assert_eq!(
+            Transport::from_str("quic://127.0.0.1:14567").unwrap(),
+            Transport::Quic("127.0.0.1:14567".to_string())
+        );
+        #[cfg(all(unix, feature = "unix"))]
+        assert_eq!(
+            Transport::from_str("unix:///run/broker.sock").unwrap(),
+            Transport::Unix("/run/broker.sock".to_string())
+        );
+        #[cfg(feature = "udp")]
+        assert_eq!(
+            Transport::from_str("udp://127.0.0.1:1884")
+                .unwrap()
+                .protocol(),
+            Protocol::MqttSn
+        );
+        assert!(Transport::from_str("carrier-pigeon://127.0.0.1").is_err());
+    }
+
+    #[cfg(feature = "tls")]
+    #[test]
+    fn test_host_of() {
+        assert_eq!(host_of("127.0.0.1:1883"), "127.0.0.1");
+        assert_eq!(host_of("broker.example.com:8883"), "broker.example.com");
+        assert_eq!(host_of("[::1]:8883"), "::1");
+        assert_eq!(host_of("::1"), "::1");
+        assert_eq!(host_of("localhost"), "localhost");
+    }
+}
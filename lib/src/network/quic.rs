@@ -0,0 +1,292 @@
+use super::tls::TlsConfig;
+use crate::markov::ByteStream;
+use clap::Args;
+use color_eyre::eyre::{bail, eyre};
+use color_eyre::Result;
+use quinn::{ClientConfig, Connection, Endpoint, RecvStream, SendStream};
+use std::io;
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::net::lookup_host;
+use tokio::sync::OnceCell;
+
+/// The ALPN protocol id of MQTT over QUIC
+const MQTT_ALPN: &[u8] = b"mqtt";
+
+/// Command line options for QUIC connections
+#[derive(Args, Debug, Clone)]
+pub struct QuicOptions {
+    /// Number of bidirectional streams opened per QUIC connection. Writes are spread over the
+    /// streams round-robin, starting with the first one
+    #[arg(long, default_value_t = 1)]
+    pub quic_streams: usize,
+}
+
+impl Default for QuicOptions {
+    fn default() -> Self {
+        Self { quic_streams: 1 }
+    }
+}
+
+/// The QUIC configuration shared by all connections. The endpoints are only bound once, so every
+/// connection doesn't need its own UDP socket.
+#[derive(Debug, Clone)]
+pub struct QuicConfig {
+    client_config: ClientConfig,
+    streams: usize,
+    tls: TlsConfig,
+    endpoint_v4: Arc<OnceCell<Endpoint>>,
+    endpoint_v6: Arc<OnceCell<Endpoint>>,
+}
+
+impl Default for QuicConfig {
+    fn default() -> Self {
+        Self::new(&QuicOptions::default(), &TlsConfig::default())
+            .expect("The default QUIC config is valid")
+    }
+}
+
+impl QuicConfig {
+    /// Uses the TLS configuration of the TLS transport, so certificates and verification work the
+    /// same for both
+    pub fn new(options: &QuicOptions, tls: &TlsConfig) -> Result<Self> {
+        if options.quic_streams == 0 {
+            bail!("QUIC needs at least one stream");
+        }
+        let mut crypto = tls.client_config().clone();
+        crypto.alpn_protocols = vec![MQTT_ALPN.to_vec()];
+        Ok(Self {
+            client_config: ClientConfig::new(Arc::new(crypto)),
+            streams: options.quic_streams,
+            tls: tls.clone(),
+            endpoint_v4: Default::default(),
+            endpoint_v6: Default::default(),
+        })
+    }
+
+    async fn endpoint(&self, remote: SocketAddr) -> Result<&Endpoint> {
+        let (cell, local) = if remote.is_ipv6() {
+            (
+                &self.endpoint_v6,
+                SocketAddr::from((Ipv6Addr::UNSPECIFIED, 0)),
+            )
+        } else {
+            (
+                &self.endpoint_v4,
+                SocketAddr::from((Ipv4Addr::UNSPECIFIED, 0)),
+            )
+        };
+        Ok(cell
+            .get_or_try_init(|| async { Endpoint::client(local) })
+            .await?)
+    }
+}
+
+/// A QUIC connection to the broker with one or more bidirectional streams
+#[derive(Debug)]
+pub struct QuicByteStream {
+    // Keeps the connection open as long as the streams are used
+    _connection: Connection,
+    streams: Vec<(SendStream, RecvStream)>,
+    // Streams the broker has closed
+    finished: Vec<bool>,
+    next_write: usize,
+    next_read: usize,
+}
+
+impl AsyncRead for QuicByteStream {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let stream_count = self.streams.len();
+        for offset in 0..stream_count {
+            // Start at a different stream each time so no stream can starve the others
+            let index = (self.next_read + offset) % stream_count;
+            if self.finished[index] {
+                continue;
+            }
+            let filled = buf.filled().len();
+            match Pin::new(&mut self.streams[index].1).poll_read(cx, buf) {
+                Poll::Ready(Ok(())) if buf.filled().len() == filled => {
+                    self.finished[index] = true;
+                }
+                Poll::Ready(result) => {
+                    self.next_read = (index + 1) % stream_count;
+                    return Poll::Ready(result);
+                }
+                Poll::Pending => {}
+            }
+        }
+        if self.finished.iter().all(|finished| *finished) {
+            // Every stream is closed, so this is the end of the connection
+            Poll::Ready(Ok(()))
+        } else {
+            Poll::Pending
+        }
+    }
+}
+
+impl AsyncWrite for QuicByteStream {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let index = self.next_write;
+        let result = Pin::new(&mut self.streams[index].0).poll_write(cx, buf);
+        // Flow control can cut the write short. The rest of the buffer follows in the next call and
+        // has to go to the same stream, otherwise the packet is split between the streams
+        if let Poll::Ready(Ok(written)) = result {
+            if written == buf.len() {
+                self.next_write = (index + 1) % self.streams.len();
+            }
+        }
+        result
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let mut pending = false;
+        for (send, _) in self.streams.iter_mut() {
+            match Pin::new(send).poll_flush(cx) {
+                Poll::Ready(Err(e)) => return Poll::Ready(Err(e)),
+                Poll::Ready(Ok(())) => {}
+                Poll::Pending => pending = true,
+            }
+        }
+        if pending {
+            Poll::Pending
+        } else {
+            Poll::Ready(Ok(()))
+        }
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let mut pending = false;
+        for (send, _) in self.streams.iter_mut() {
+            match Pin::new(send).poll_shutdown(cx) {
+                Poll::Ready(Err(e)) => return Poll::Ready(Err(e)),
+                Poll::Ready(Ok(())) => {}
+                Poll::Pending => pending = true,
+            }
+        }
+        if pending {
+            Poll::Pending
+        } else {
+            Poll::Ready(Ok(()))
+        }
+    }
+}
+
+impl ByteStream for QuicByteStream {}
+
+/// Connects to the broker via QUIC and opens the configured number of bidirectional streams
+pub async fn connect(address: &str, config: &QuicConfig) -> Result<QuicByteStream> {
+    let remote = lookup_host(address)
+        .await?
+        .next()
+        .ok_or_else(|| eyre!("Could not resolve {address}"))?;
+    let endpoint = config.endpoint(remote).await?;
+    let connection = endpoint
+        .connect_with(
+            config.client_config.clone(),
+            remote,
+            config.tls.server_host(address),
+        )?
+        .await?;
+    let mut streams = Vec::with_capacity(config.streams);
+    for _ in 0..config.streams {
+        streams.push(connection.open_bi().await?);
+    }
+    Ok(QuicByteStream {
+        _connection: connection,
+        finished: vec![false; streams.len()],
+        streams,
+        next_write: 0,
+        next_read: 0,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use quinn::ServerConfig;
+    use rustls::{Certificate, PrivateKey};
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    /// A QUIC server which echoes everything it receives on every stream
+    fn echo_server() -> SocketAddr {
+        let certificate = rcgen::generate_simple_self_signed(vec!["localhost".into()]).unwrap();
+        let mut crypto = rustls::ServerConfig::builder()
+            .with_safe_default_cipher_suites()
+            .with_safe_default_kx_groups()
+            .with_protocol_versions(&[&rustls::version::TLS13])
+            .unwrap()
+            .with_no_client_auth()
+            .with_single_cert(
+                vec![Certificate(certificate.serialize_der().unwrap())],
+                PrivateKey(certificate.serialize_private_key_der()),
+            )
+            .unwrap();
+        crypto.alpn_protocols = vec![MQTT_ALPN.to_vec()];
+        let server_config = ServerConfig::with_crypto(Arc::new(crypto));
+        let endpoint =
+            Endpoint::server(server_config, SocketAddr::from((Ipv4Addr::LOCALHOST, 0))).unwrap();
+        let address = endpoint.local_addr().unwrap();
+        tokio::spawn(async move {
+            while let Some(connecting) = endpoint.accept().await {
+                let connection = connecting.await.unwrap();
+                tokio::spawn(async move {
+                    while let Ok((mut send, mut recv)) = connection.accept_bi().await {
+                        tokio::spawn(async move {
+                            let mut buf = [0; 1024];
+                            while let Ok(Some(n)) = recv.read(&mut buf).await {
+                                send.write_all(&buf[..n]).await.unwrap();
+                            }
+                        });
+                    }
+                });
+            }
+        });
+        address
+    }
+
+    #[tokio::test]
+    async fn test_quic_partial_writes_stay_on_the_stream() {
+        let address = echo_server().to_string();
+        let config =
+            QuicConfig::new(&QuicOptions { quic_streams: 2 }, &TlsConfig::default()).unwrap();
+        let mut stream = connect(&address, &config).await.unwrap();
+        // Larger than the flow control window, so the write is cut short at least once
+        let packet: Vec<u8> = (0..4 << 20).map(|i| i as u8).collect();
+        let (mut read_half, mut write_half) = tokio::io::split(&mut stream);
+        let reader = async {
+            let mut echoed = vec![0; packet.len()];
+            read_half.read_exact(&mut echoed).await.unwrap();
+            echoed
+        };
+        let (echoed, _) = tokio::join!(reader, write_half.write_all(&packet));
+        assert_eq!(echoed, packet);
+        assert_eq!(stream.next_write, 1);
+    }
+
+    #[tokio::test]
+    async fn test_quic_echo() {
+        let address = echo_server().to_string();
+        for quic_streams in [1, 3] {
+            let config =
+                QuicConfig::new(&QuicOptions { quic_streams }, &TlsConfig::default()).unwrap();
+            let mut stream = connect(&address, &config).await.unwrap();
+            for _ in 0..quic_streams {
+                stream.write_all(&[192, 0]).await.unwrap();
+                let mut buf = [0; 2];
+                stream.read_exact(&mut buf).await.unwrap();
+                assert_eq!(buf, [192, 0]);
+            }
+        }
+    }
+}
@@ -0,0 +1,131 @@
+use clap::Args;
+use color_eyre::eyre::{bail, eyre};
+use color_eyre::Result;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use tokio::net::{lookup_host, TcpSocket, TcpStream};
+
+/// Command line options for connections over TCP, which includes TLS and websockets
+#[derive(Args, Debug, Clone, Default)]
+pub struct TcpOptions {
+    /// Local addresses to connect from, used round-robin. Every source address has its own range
+    /// of ephemeral ports, so more addresses means more connections before the ports run out
+    #[arg(long, value_delimiter = ',')]
+    pub source_addresses: Vec<IpAddr>,
+    /// Rotate over the loopback addresses 127.0.0.1 to 127.0.0.N. Only useful for local brokers
+    #[arg(long, conflicts_with = "source_addresses")]
+    pub loopback_sources: Option<u8>,
+    /// Close connections with FIN instead of RST. Closed connections then linger in TIME_WAIT and
+    /// keep their port busy
+    #[arg(long)]
+    pub graceful_close: bool,
+}
+
+/// The TCP configuration shared by all connections
+#[derive(Debug, Clone)]
+pub struct TcpConfig {
+    source_addresses: Arc<[IpAddr]>,
+    next_source: Arc<AtomicUsize>,
+    graceful_close: bool,
+}
+
+impl Default for TcpConfig {
+    fn default() -> Self {
+        Self {
+            source_addresses: Arc::new([]),
+            next_source: Default::default(),
+            graceful_close: false,
+        }
+    }
+}
+
+impl TcpConfig {
+    pub fn new(options: &TcpOptions) -> Result<Self> {
+        let source_addresses = match options.loopback_sources {
+            Some(0) => bail!("--loopback-sources needs at least one address"),
+            Some(count) => (1..=count)
+                .map(|host| IpAddr::V4(Ipv4Addr::new(127, 0, 0, host)))
+                .collect(),
+            None => options.source_addresses.clone(),
+        };
+        Ok(Self {
+            source_addresses: source_addresses.into(),
+            next_source: Default::default(),
+            graceful_close: options.graceful_close,
+        })
+    }
+
+    /// The next source address of the same family as the remote address, if any are configured
+    fn next_source(&self, remote: &SocketAddr) -> Option<IpAddr> {
+        for _ in 0..self.source_addresses.len() {
+            let index = self.next_source.fetch_add(1, Ordering::Relaxed);
+            let source = self.source_addresses[index % self.source_addresses.len()];
+            if source.is_ipv4() == remote.is_ipv4() {
+                return Some(source);
+            }
+        }
+        None
+    }
+}
+
+/// Connects to the broker. SO_REUSEADDR is set and unless graceful close is configured SO_LINGER
+/// is set to zero, so closed connections are reset and don't end up in TIME_WAIT
+pub async fn connect(address: &str, config: &TcpConfig) -> Result<TcpStream> {
+    let remote = lookup_host(address)
+        .await?
+        .next()
+        .ok_or_else(|| eyre!("Could not resolve {address}"))?;
+    let socket = if remote.is_ipv4() {
+        TcpSocket::new_v4()?
+    } else {
+        TcpSocket::new_v6()?
+    };
+    socket.set_reuseaddr(true)?;
+    if !config.graceful_close {
+        socket.set_zero_linger()?;
+    }
+    if let Some(source) = config.next_source(&remote) {
+        socket.bind(SocketAddr::new(source, 0))?;
+    }
+    Ok(socket.connect(remote).await?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::net::TcpListener;
+
+    #[test]
+    fn test_loopback_sources() {
+        let config = TcpConfig::new(&TcpOptions {
+            loopback_sources: Some(3),
+            ..Default::default()
+        })
+        .unwrap();
+        let remote = SocketAddr::from((Ipv4Addr::LOCALHOST, 1883));
+        let sources: Vec<_> = (0..4).map(|_| config.next_source(&remote)).collect();
+        assert_eq!(
+            sources,
+            [1, 2, 3, 1].map(|host| Some(IpAddr::V4(Ipv4Addr::new(127, 0, 0, host))))
+        );
+        // There is no IPv6 source address to use
+        assert_eq!(config.next_source(&"[::1]:1883".parse().unwrap()), None);
+    }
+
+    #[tokio::test]
+    async fn test_connect_from_source() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let config = TcpConfig::new(&TcpOptions {
+            loopback_sources: Some(2),
+            ..Default::default()
+        })
+        .unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        for host in [1, 2] {
+            let _stream = connect(&address, &config).await.unwrap();
+            let (_, peer) = listener.accept().await.unwrap();
+            assert_eq!(peer.ip(), IpAddr::V4(Ipv4Addr::new(127, 0, 0, host)));
+        }
+    }
+}
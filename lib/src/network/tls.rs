@@ -0,0 +1,298 @@
+use super::tcp::TcpConfig;
+use super::{host_of, tcp, TlsLayer};
+use crate::markov::ByteStream;
+use clap::Args;
+use color_eyre::eyre::{bail, eyre, WrapErr};
+use color_eyre::Result;
+use futures::future::BoxFuture;
+use rustls::client::{ServerCertVerified, ServerCertVerifier, WebPkiVerifier};
+use rustls::{
+    Certificate, ClientConfig, Error, OwnedTrustAnchor, PrivateKey, RootCertStore, ServerName,
+};
+use std::fs::File;
+use std::io;
+use std::io::BufReader;
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
+use std::pin::Pin;
+use std::str::FromStr;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::time::SystemTime;
+use tokio::io::{AsyncRead, AsyncWrite, AsyncWriteExt, ReadBuf};
+use tokio::net::TcpStream;
+use tokio_rustls::client::TlsStream;
+use tokio_rustls::TlsConnector;
+
+struct NoCertificateVerifier;
+
+impl ServerCertVerifier for NoCertificateVerifier {
+    fn verify_server_cert(
+        &self,
+        _end_entity: &Certificate,
+        _intermediates: &[Certificate],
+        _server_name: &ServerName,
+        _scts: &mut dyn Iterator<Item = &[u8]>,
+        _ocsp_response: &[u8],
+        _now: SystemTime,
+    ) -> std::result::Result<ServerCertVerified, Error> {
+        Ok(ServerCertVerified::assertion())
+    }
+}
+
+/// Command line options for TLS connections
+#[derive(Args, Debug, Clone, Default)]
+pub struct TlsOptions {
+    /// Verify the broker certificate instead of accepting any certificate
+    #[arg(long)]
+    pub tls_verify: bool,
+    /// PEM file with the CA certificates used to verify the broker. Implies --tls-verify
+    #[arg(long)]
+    pub ca_file: Option<PathBuf>,
+    /// PEM file with the client certificate chain presented to the broker
+    #[arg(long, requires = "client_key")]
+    pub client_cert: Option<PathBuf>,
+    /// PEM file with the private key of the client certificate
+    #[arg(long, requires = "client_cert")]
+    pub client_key: Option<PathBuf>,
+    /// The name sent via SNI and used for verification. Defaults to the host of the target
+    #[arg(long)]
+    pub server_name: Option<String>,
+    /// Also fuzz the TLS layer by injecting broken records, alerts and handshakes into the session
+    #[arg(long)]
+    pub fuzz_tls_layer: bool,
+}
+
+/// The TLS configuration shared by all connections
+#[derive(Debug, Clone)]
+pub struct TlsConfig {
+    client_config: Arc<ClientConfig>,
+    server_name: Option<String>,
+    fuzz_tls_layer: bool,
+}
+
+impl Default for TlsConfig {
+    fn default() -> Self {
+        let client_config = ClientConfig::builder()
+            .with_safe_defaults()
+            .with_custom_certificate_verifier(Arc::new(NoCertificateVerifier))
+            .with_no_client_auth();
+        Self {
+            client_config: Arc::new(client_config),
+            server_name: None,
+            fuzz_tls_layer: false,
+        }
+    }
+}
+
+impl TlsConfig {
+    pub fn new(options: &TlsOptions) -> Result<Self> {
+        let verifier: Arc<dyn ServerCertVerifier> = if options.tls_verify
+            || options.ca_file.is_some()
+        {
+            let mut roots = RootCertStore::empty();
+            match &options.ca_file {
+                Some(ca_file) => {
+                    for certificate in read_certificates(ca_file)? {
+                        roots.add(&certificate)?;
+                    }
+                }
+                None => roots.add_trust_anchors(webpki_roots::TLS_SERVER_ROOTS.iter().map(|ta| {
+                    OwnedTrustAnchor::from_subject_spki_name_constraints(
+                        ta.subject,
+                        ta.spki,
+                        ta.name_constraints,
+                    )
+                })),
+            }
+            Arc::new(WebPkiVerifier::new(roots, None))
+        } else {
+            // Verifying the broker certificate would just be wasted iterations
+            Arc::new(NoCertificateVerifier)
+        };
+        let builder = ClientConfig::builder()
+            .with_safe_defaults()
+            .with_custom_certificate_verifier(verifier);
+        let client_config = match (&options.client_cert, &options.client_key) {
+            (Some(cert), Some(key)) => {
+                builder.with_client_auth_cert(read_certificates(cert)?, read_private_key(key)?)?
+            }
+            (None, None) => builder.with_no_client_auth(),
+            _ => bail!("A client certificate needs both --client-cert and --client-key"),
+        };
+        Ok(Self {
+            client_config: Arc::new(client_config),
+            server_name: options.server_name.clone(),
+            fuzz_tls_layer: options.fuzz_tls_layer,
+        })
+    }
+
+    pub fn fuzz_tls_layer(&self) -> bool {
+        self.fuzz_tls_layer
+    }
+
+    #[cfg(feature = "quic")]
+    pub(crate) fn client_config(&self) -> &ClientConfig {
+        &self.client_config
+    }
+
+    /// The host used for SNI, either the configured one or the host of the address
+    pub(crate) fn server_host<'a>(&'a self, address: &'a str) -> &'a str {
+        self.server_name
+            .as_deref()
+            .unwrap_or_else(|| host_of(address))
+    }
+
+    fn server_name(&self, address: &str) -> Result<ServerName> {
+        let host = self.server_host(address);
+        match IpAddr::from_str(host) {
+            Ok(ip) => Ok(ServerName::IpAddress(ip)),
+            Err(_) => ServerName::try_from(host)
+                .wrap_err_with(|| format!("Invalid TLS server name {host}")),
+        }
+    }
+}
+
+fn read_certificates(path: &Path) -> Result<Vec<Certificate>> {
+    let mut reader = BufReader::new(
+        File::open(path).wrap_err_with(|| format!("Could not open {}", path.display()))?,
+    );
+    let certificates = rustls_pemfile::certs(&mut reader)?;
+    if certificates.is_empty() {
+        bail!("No certificates found in {}", path.display());
+    }
+    Ok(certificates.into_iter().map(Certificate).collect())
+}
+
+fn read_private_key(path: &Path) -> Result<PrivateKey> {
+    let mut reader = BufReader::new(
+        File::open(path).wrap_err_with(|| format!("Could not open {}", path.display()))?,
+    );
+    while let Some(item) = rustls_pemfile::read_one(&mut reader)? {
+        match item {
+            rustls_pemfile::Item::PKCS8Key(key)
+            | rustls_pemfile::Item::RSAKey(key)
+            | rustls_pemfile::Item::ECKey(key) => return Ok(PrivateKey(key)),
+            _ => {}
+        }
+    }
+    Err(eyre!("No private key found in {}", path.display()))
+}
+
+/// Connects to the broker using TLS. Unless configured otherwise the server certificate is not
+/// verified
+pub async fn connect(
+    address: &str,
+    tcp: &TcpConfig,
+    config: &TlsConfig,
+) -> Result<TlsStream<TcpStream>> {
+    let server_name = config.server_name(address)?;
+    let socket = tcp::connect(address, tcp).await?;
+    let connector = TlsConnector::from(config.client_config.clone());
+    let stream = connector.connect(server_name, socket).await?;
+    Ok(stream)
+}
+
+impl ByteStream for TlsStream<TcpStream> {}
+
+/// A TLS stream which exposes its TLS layer to the state machine, so it can be fuzzed as well
+#[derive(Debug)]
+pub struct TlsFuzzStream {
+    inner: TlsStream<TcpStream>,
+}
+
+impl TlsFuzzStream {
+    pub fn new(inner: TlsStream<TcpStream>) -> Self {
+        Self { inner }
+    }
+}
+
+impl AsyncRead for TlsFuzzStream {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_read(cx, buf)
+    }
+}
+
+impl AsyncWrite for TlsFuzzStream {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.inner).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
+
+impl TlsLayer for TlsFuzzStream {
+    fn write_raw<'a>(&'a mut self, bytes: &'a [u8]) -> BoxFuture<'a, io::Result<()>> {
+        Box::pin(async move {
+            // Whatever the session still buffers has to go out first
+            self.inner.flush().await?;
+            self.inner.get_mut().0.write_all(bytes).await
+        })
+    }
+
+    fn close_notify(&mut self) -> BoxFuture<'_, io::Result<()>> {
+        Box::pin(async move {
+            self.inner.get_mut().1.send_close_notify();
+            self.inner.flush().await
+        })
+    }
+}
+
+impl ByteStream for TlsFuzzStream {
+    fn tls_layer(&mut self) -> Option<&mut dyn TlsLayer> {
+        Some(self)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_server_name() {
+        let config = TlsConfig::default();
+        assert_eq!(
+            config.server_name("127.0.0.1:8883").unwrap(),
+            ServerName::IpAddress(IpAddr::from_str("127.0.0.1").unwrap())
+        );
+        assert_eq!(
+            config.server_name("[::1]:8883").unwrap(),
+            ServerName::IpAddress(IpAddr::from_str("::1").unwrap())
+        );
+        assert_eq!(
+            config.server_name("broker.example.com:8883").unwrap(),
+            ServerName::try_from("broker.example.com").unwrap()
+        );
+        let config = TlsConfig {
+            server_name: Some("mqtt.example.com".to_string()),
+            ..Default::default()
+        };
+        assert_eq!(
+            config.server_name("127.0.0.1:8883").unwrap(),
+            ServerName::try_from("mqtt.example.com").unwrap()
+        );
+    }
+
+    #[test]
+    fn test_client_cert_requires_key() {
+        let options = TlsOptions {
+            client_cert: Some(PathBuf::from("client.pem")),
+            ..Default::default()
+        };
+        assert!(TlsConfig::new(&options).is_err());
+    }
+}
@@ -0,0 +1,86 @@
+use crate::markov::ByteStream;
+use color_eyre::eyre::eyre;
+use color_eyre::Result;
+use std::io;
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::net::{lookup_host, UdpSocket};
+
+/// A connected UDP socket. Every write is sent as one datagram and every read returns at most one
+/// datagram, so packets keep their boundaries.
+#[derive(Debug)]
+pub struct UdpByteStream {
+    socket: UdpSocket,
+}
+
+impl AsyncRead for UdpByteStream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        self.socket.poll_recv(cx, buf)
+    }
+}
+
+impl AsyncWrite for UdpByteStream {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        self.socket.poll_send(cx, buf)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl ByteStream for UdpByteStream {}
+
+pub async fn connect(address: &str) -> Result<UdpByteStream> {
+    let remote = lookup_host(address)
+        .await?
+        .next()
+        .ok_or_else(|| eyre!("Could not resolve {address}"))?;
+    let local = if remote.is_ipv6() {
+        SocketAddr::from((Ipv6Addr::UNSPECIFIED, 0))
+    } else {
+        SocketAddr::from((Ipv4Addr::UNSPECIFIED, 0))
+    };
+    let socket = UdpSocket::bind(local).await?;
+    socket.connect(remote).await?;
+    Ok(UdpByteStream { socket })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    #[tokio::test]
+    async fn test_datagram_boundaries() {
+        let server = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let mut stream = connect(&server.local_addr().unwrap().to_string())
+            .await
+            .unwrap();
+        stream.write_all(&[2, 22]).await.unwrap();
+        stream.write_all(&[2, 24]).await.unwrap();
+        let mut buf = [0; 16];
+        let (n, client) = server.recv_from(&mut buf).await.unwrap();
+        assert_eq!(&buf[..n], &[2, 22]);
+        server.send_to(&[2, 23], client).await.unwrap();
+        server.send_to(&[3, 5, 0], client).await.unwrap();
+        let n = stream.read(&mut buf).await.unwrap();
+        assert_eq!(&buf[..n], &[2, 23]);
+        let n = stream.read(&mut buf).await.unwrap();
+        assert_eq!(&buf[..n], &[3, 5, 0]);
+    }
+}
@@ -0,0 +1,41 @@
+use crate::markov::ByteStream;
+use color_eyre::eyre::WrapErr;
+use color_eyre::Result;
+use tokio::net::UnixStream;
+
+/// Connects to a broker listening on a Unix domain socket. No ports are involved, so this can't
+/// run out of ephemeral ports
+pub async fn connect(path: &str) -> Result<UnixStream> {
+    let stream = UnixStream::connect(path)
+        .await
+        .wrap_err_with(|| format!("Could not connect to {path}"))?;
+    Ok(stream)
+}
+
+impl ByteStream for UnixStream {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::UnixListener;
+
+    #[tokio::test]
+    async fn test_unix_echo() {
+        let path = std::env::temp_dir().join(format!("rusty-fume-{}.sock", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let listener = UnixListener::bind(&path).unwrap();
+        tokio::spawn(async move {
+            let (mut socket, _) = listener.accept().await.unwrap();
+            let mut buf = [0; 1024];
+            let n = socket.read(&mut buf).await.unwrap();
+            socket.write_all(&buf[..n]).await.unwrap();
+        });
+        let mut stream = connect(path.to_str().unwrap()).await.unwrap();
+        stream.write_all(&[192, 0]).await.unwrap();
+        let mut buf = [0; 2];
+        stream.read_exact(&mut buf).await.unwrap();
+        assert_eq!(buf, [192, 0]);
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,185 @@
+use super::Target;
+use crate::markov::ByteStream;
+use color_eyre::eyre::{bail, eyre};
+use color_eyre::Result;
+use futures::{SinkExt, StreamExt};
+use std::fmt::Debug;
+use std::io;
+use std::pin::Pin;
+use std::task::{ready, Context, Poll};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio_tungstenite::tungstenite::client::IntoClientRequest;
+use tokio_tungstenite::tungstenite::handshake::client::Request;
+use tokio_tungstenite::tungstenite::http::HeaderValue;
+use tokio_tungstenite::tungstenite::protocol::WebSocketConfig;
+use tokio_tungstenite::tungstenite::{Error as WsError, Message};
+use tokio_tungstenite::WebSocketStream;
+
+/// Adapter which exposes a websocket connection as a plain byte stream. Every write is sent
+/// as a single binary frame and incoming frames are concatenated, so MQTT packets can be split
+/// over frames just like over TCP.
+#[derive(Debug)]
+pub struct WebSocketByteStream<S> {
+    inner: WebSocketStream<S>,
+    // Bytes of the last received frame that haven't been read yet
+    read_buffer: Vec<u8>,
+}
+
+impl<S> WebSocketByteStream<S> {
+    pub fn new(inner: WebSocketStream<S>) -> Self {
+        Self {
+            inner,
+            read_buffer: Vec::new(),
+        }
+    }
+}
+
+fn to_io_error(e: WsError) -> io::Error {
+    match e {
+        WsError::Io(e) => e,
+        e => io::Error::other(e),
+    }
+}
+
+impl<S> AsyncRead for WebSocketByteStream<S>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        while self.read_buffer.is_empty() {
+            match ready!(self.inner.poll_next_unpin(cx)) {
+                Some(Ok(Message::Binary(data))) => self.read_buffer = data,
+                Some(Ok(Message::Text(text))) => self.read_buffer = text.into_bytes(),
+                // Pings are answered by tungstenite itself
+                Some(Ok(Message::Ping(_) | Message::Pong(_) | Message::Frame(_))) => {}
+                Some(Ok(Message::Close(_))) | None => return Poll::Ready(Ok(())),
+                Some(Err(WsError::ConnectionClosed)) => return Poll::Ready(Ok(())),
+                Some(Err(e)) => return Poll::Ready(Err(to_io_error(e))),
+            }
+        }
+        let len = buf.remaining().min(self.read_buffer.len());
+        buf.put_slice(&self.read_buffer[..len]);
+        self.read_buffer.drain(..len);
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<S> AsyncWrite for WebSocketByteStream<S>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        ready!(self.inner.poll_ready_unpin(cx)).map_err(to_io_error)?;
+        self.inner
+            .start_send_unpin(Message::Binary(buf.to_vec()))
+            .map_err(to_io_error)?;
+        // The frame is already queued, so we only try to push it out here. A pending flush is
+        // finished by the next read or write.
+        if let Poll::Ready(Err(e)) = self.inner.poll_flush_unpin(cx) {
+            return Poll::Ready(Err(to_io_error(e)));
+        }
+        Poll::Ready(Ok(buf.len()))
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        self.inner.poll_flush_unpin(cx).map_err(to_io_error)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        self.inner.poll_close_unpin(cx).map_err(to_io_error)
+    }
+}
+
+impl<S> ByteStream for WebSocketByteStream<S> where S: AsyncRead + AsyncWrite + Unpin + Debug + Send {}
+
+/// Connects to the broker via websocket, e.g. `ws://127.0.0.1:8080/mqtt`, using the `mqtt`
+/// subprotocol. `wss://` urls are tunneled through TLS if the `tls` feature is enabled.
+pub async fn connect(url: &str, target: &Target) -> Result<Box<dyn ByteStream>> {
+    let request = url.into_client_request()?;
+    let uri = request.uri();
+    let authority = uri
+        .authority()
+        .ok_or_else(|| eyre!("Missing host in websocket url {url}"))?;
+    let secure = match uri.scheme_str() {
+        Some("ws") => false,
+        Some("wss") => true,
+        _ => bail!("Unsupported websocket url {url}"),
+    };
+    let port = authority
+        .port_u16()
+        .unwrap_or(if secure { 443 } else { 80 });
+    let address = format!("{}:{}", authority.host(), port);
+    if secure {
+        #[cfg(feature = "tls")]
+        {
+            let socket = super::tls::connect(&address, &target.tcp, &target.tls).await?;
+            return Ok(Box::new(handshake(request, socket).await?));
+        }
+        #[cfg(not(feature = "tls"))]
+        {
+            let _ = target;
+            bail!("wss:// requires the tls feature");
+        }
+    }
+    let socket = super::tcp::connect(&address, &target.tcp).await?;
+    Ok(Box::new(handshake(request, socket).await?))
+}
+
+/// Performs the websocket handshake on an already established connection
+pub async fn handshake<S>(mut request: Request, socket: S) -> Result<WebSocketByteStream<S>>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    request
+        .headers_mut()
+        .insert("Sec-WebSocket-Protocol", HeaderValue::from_static("mqtt"));
+    // Don't buffer frames, every write should reach the broker immediately
+    let config = WebSocketConfig {
+        write_buffer_size: 0,
+        ..Default::default()
+    };
+    let (stream, _) =
+        tokio_tungstenite::client_async_with_config(request, socket, Some(config)).await?;
+    Ok(WebSocketByteStream::new(stream))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::network::Transport;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpListener;
+
+    #[tokio::test]
+    async fn test_websocket_echo() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            let (socket, _) = listener.accept().await.unwrap();
+            let mut ws = tokio_tungstenite::accept_async(socket).await.unwrap();
+            while let Some(Ok(message)) = ws.next().await {
+                if message.is_binary() {
+                    ws.send(message).await.unwrap();
+                }
+            }
+        });
+        let mut stream = connect(
+            &format!("ws://{address}/mqtt"),
+            &Target::new(Transport::WebSocket(String::new())),
+        )
+        .await
+        .unwrap();
+        stream.write_all(&[192, 0]).await.unwrap();
+        let mut buf = [0; 2];
+        stream.read_exact(&mut buf).await.unwrap();
+        assert_eq!(buf, [192, 0]);
+    }
+}
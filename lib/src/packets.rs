@@ -1,66 +1,129 @@
-use crate::markov::MAX_PACKETS;
+use crate::mqtt::signature::Novelty;
 use serde::{Deserialize, Serialize};
 use serde_with::formats::CommaSeparator;
 use serde_with::serde_as;
 use serde_with::StringWithSeparator;
-use std::cmp::min;
 use std::collections::BTreeMap;
 use std::fmt::Display;
 use std::path::Path;
 use tokio::fs::File;
 use tokio::io::AsyncReadExt;
+
+/// The version of the packet pool format. Version 1 stored every chain as an array of
+/// [MAX_PACKETS](crate::markov::MAX_PACKETS) packets, with empty packets marking the free slots
+const PACKET_QUEUE_VERSION: u32 = 2;
+
+/// A chain of packets which are sent one after another
 #[serde_as]
 #[derive(Debug, PartialEq, Eq, Clone, Hash, Default, Serialize, Deserialize)]
 pub struct Packets {
-    #[serde_as(as = "[StringWithSeparator::<CommaSeparator, u8>; MAX_PACKETS]")]
-    pub(crate) inner: [Vec<u8>; MAX_PACKETS],
+    #[serde_as(as = "Vec<StringWithSeparator::<CommaSeparator, u8>>")]
+    pub(crate) inner: Vec<Vec<u8>>,
 }
 impl Display for Packets {
     // Hex dump the packets
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         let mut s = String::new();
-        for i in 0..MAX_PACKETS {
-            if !self.inner[i].is_empty() {
-                s.push_str(hex::encode(&self.inner[i]).as_str());
-                s.push('\n');
-            }
+        for packet in &self.inner {
+            s.push_str(hex::encode(packet).as_str());
+            s.push('\n');
         }
         write!(f, "{}", s)
     }
 }
 impl Packets {
     pub fn append(&mut self, packet: &[u8]) {
-        // Search the first free slot and insert it there
-        let size = self.size();
-        if size < MAX_PACKETS {
-            self.inner[size] = packet.to_vec();
-        }
+        self.inner.push(packet.to_vec());
     }
-    pub fn is_full(&self) -> bool {
-        self.inner.iter().all(|x| !x.is_empty())
+    pub fn len(&self) -> usize {
+        self.inner.len()
     }
-    pub fn size(&self) -> usize {
-        min(1, self.inner.iter().filter(|x| !x.is_empty()).count())
+    pub fn is_empty(&self) -> bool {
+        self.inner.is_empty()
     }
     pub fn new() -> Self {
-        Self {
-            inner: Default::default(),
-        }
+        Self { inner: Vec::new() }
     }
 }
 
 #[serde_as]
-#[derive(Debug, PartialEq, Eq, Clone, Hash, Default, Serialize, Deserialize)]
+#[derive(Debug, PartialEq, Eq, Clone, Hash, Serialize, Deserialize)]
 pub struct PacketQueue {
+    // Files without a version are from before the chains had a variable length
+    #[serde(default = "legacy_version")]
+    version: u32,
+    // The chains by the response which made them a new behavior
     #[serde_as(as = "BTreeMap<StringWithSeparator::<CommaSeparator, u8>, _>")]
     pub(crate) inner: BTreeMap<Vec<u8>, Packets>,
+    // How the responses are turned into keys
+    #[serde(skip)]
+    pub(crate) novelty: Novelty,
+}
+
+impl Default for PacketQueue {
+    fn default() -> Self {
+        Self {
+            version: PACKET_QUEUE_VERSION,
+            inner: Default::default(),
+            novelty: Default::default(),
+        }
+    }
+}
+
+fn legacy_version() -> u32 {
+    1
 }
 
 impl PacketQueue {
     pub async fn read_from_file(path: impl AsRef<Path>) -> color_eyre::Result<Self> {
         let mut content = String::new();
         File::open(path).await?.read_to_string(&mut content).await?;
-        let queue = toml::from_str(&content)?;
-        Ok(queue)
+        let queue: Self = toml::from_str(&content)?;
+        Ok(queue.migrate())
+    }
+
+    pub fn with_novelty(mut self, novelty: Novelty) -> Self {
+        self.novelty = novelty;
+        self
+    }
+
+    /// Converts a queue read from an older file to the current format
+    fn migrate(mut self) -> Self {
+        if self.version < 2 {
+            // The empty packets were only placeholders for free slots
+            for packets in self.inner.values_mut() {
+                packets.inner.retain(|packet| !packet.is_empty());
+            }
+        }
+        self.version = PACKET_QUEUE_VERSION;
+        self
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_migrate_fixed_size_chains() {
+        let legacy = r#"
+            [inner."224,0"]
+            inner = ["16,2,0,0", "224,0", "", "", "", "", "", "", "", ""]
+        "#;
+        let queue = toml::from_str::<PacketQueue>(legacy).unwrap().migrate();
+        assert_eq!(
+            queue.inner[&vec![224, 0]].inner,
+            vec![vec![16, 2, 0, 0], vec![224, 0]]
+        );
+        // Empty packets are kept in the current format
+        let mut packets = Packets::new();
+        packets.append(&[]);
+        let mut queue = PacketQueue::default();
+        queue.inner.insert(vec![0], packets.clone());
+        let serialized = toml::to_string(&queue).unwrap();
+        let queue = toml::from_str::<PacketQueue>(&serialized)
+            .unwrap()
+            .migrate();
+        assert_eq!(queue.inner[&vec![0]], packets);
     }
 }
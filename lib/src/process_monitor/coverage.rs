@@ -0,0 +1,192 @@
+//! Edge coverage of brokers which are instrumented like AFL does it, e.g. with `afl-cc` or another
+//! SanitizerCoverage runtime that writes into the map from `__AFL_SHM_ID`. The broker increments a
+//! byte of a shared memory map for every edge it takes, after every chain the hits are collected and
+//! compared with the ones seen so far.
+//! All threads fuzz the same broker, so new edges are attributed to whichever chain collects them
+//! first. Fuzz with a single thread for exact attribution.
+use color_eyre::eyre::bail;
+use color_eyre::Result;
+use std::sync::atomic::{AtomicU8, Ordering};
+use std::sync::Mutex;
+
+/// The map size of AFL
+pub const DEFAULT_MAP_SIZE: usize = 1 << 16;
+/// The environment variable the instrumentation reads the id of the shared memory from
+pub const SHM_ENV_VAR: &str = "__AFL_SHM_ID";
+/// AFL++ instrumentation also needs to know the size of the map
+pub const MAP_SIZE_ENV_VAR: &str = "AFL_MAP_SIZE";
+
+/// A System V shared memory segment the broker attaches to
+#[derive(Debug)]
+struct SharedMemory {
+    id: i32,
+    ptr: *mut u8,
+    size: usize,
+}
+
+// The memory is only accessed through atomics
+unsafe impl Send for SharedMemory {}
+unsafe impl Sync for SharedMemory {}
+
+impl SharedMemory {
+    #[cfg(unix)]
+    fn new(size: usize) -> Result<Self> {
+        // SAFETY: A new private segment is created and attached, failures are checked
+        unsafe {
+            let id = libc::shmget(
+                libc::IPC_PRIVATE,
+                size,
+                libc::IPC_CREAT | libc::IPC_EXCL | 0o600,
+            );
+            if id < 0 {
+                bail!(
+                    "Could not create the coverage map: {}",
+                    std::io::Error::last_os_error()
+                );
+            }
+            let ptr = libc::shmat(id, std::ptr::null(), 0);
+            if ptr as isize == -1 {
+                let error = std::io::Error::last_os_error();
+                libc::shmctl(id, libc::IPC_RMID, std::ptr::null_mut());
+                bail!("Could not attach the coverage map: {}", error);
+            }
+            Ok(Self {
+                id,
+                ptr: ptr as *mut u8,
+                size,
+            })
+        }
+    }
+
+    #[cfg(not(unix))]
+    fn new(_size: usize) -> Result<Self> {
+        bail!("Coverage feedback needs System V shared memory, which is only available on unix")
+    }
+
+    fn bytes(&self) -> &[AtomicU8] {
+        // SAFETY: The segment stays attached until self is dropped and the broker writes to it
+        // concurrently, so it is only accessed through atomics
+        unsafe { std::slice::from_raw_parts(self.ptr as *const AtomicU8, self.size) }
+    }
+}
+
+impl Drop for SharedMemory {
+    fn drop(&mut self) {
+        // SAFETY: The segment was attached in new. It is freed once the broker detaches as well
+        #[cfg(unix)]
+        unsafe {
+            libc::shmdt(self.ptr as *const libc::c_void);
+            libc::shmctl(self.id, libc::IPC_RMID, std::ptr::null_mut());
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Seen {
+    // The hit count buckets seen for every edge
+    buckets: Vec<u8>,
+    // How many edge and bucket pairs have been seen
+    count: usize,
+}
+
+#[derive(Debug)]
+pub struct Coverage {
+    map: SharedMemory,
+    seen: Mutex<Seen>,
+}
+
+impl Coverage {
+    pub fn new(map_size: usize) -> Result<Self> {
+        if map_size == 0 {
+            bail!("The coverage map can't be empty");
+        }
+        Ok(Self {
+            map: SharedMemory::new(map_size)?,
+            seen: Mutex::new(Seen {
+                buckets: vec![0; map_size],
+                count: 0,
+            }),
+        })
+    }
+
+    /// The environment variables which tell the instrumented broker where the map is
+    pub fn env(&self) -> [(&'static str, String); 2] {
+        [
+            (SHM_ENV_VAR, self.map.id.to_string()),
+            (MAP_SIZE_ENV_VAR, self.map.size.to_string()),
+        ]
+    }
+
+    /// Collects and resets the hits since the last call. Returns the number of edge and hit count
+    /// bucket pairs seen so far, if the hits added any
+    pub fn new_coverage(&self) -> Option<usize> {
+        let mut seen = self.seen.lock().unwrap();
+        let mut new = false;
+        for (hits, seen_buckets) in self.map.bytes().iter().zip(seen.buckets.iter_mut()) {
+            if hits.load(Ordering::Relaxed) == 0 {
+                continue;
+            }
+            let bucket = bucket(hits.swap(0, Ordering::Relaxed));
+            if bucket & !*seen_buckets != 0 {
+                *seen_buckets |= bucket;
+                new = true;
+            }
+        }
+        if !new {
+            return None;
+        }
+        seen.count = seen
+            .buckets
+            .iter()
+            .map(|buckets| buckets.count_ones() as usize)
+            .sum();
+        Some(seen.count)
+    }
+
+    /// The number of edges which were taken at least once
+    pub fn edges(&self) -> usize {
+        let seen = self.seen.lock().unwrap();
+        seen.buckets.iter().filter(|buckets| **buckets != 0).count()
+    }
+}
+
+/// Sorts hit counts into the buckets of AFL, so loops which run a few more times don't count as new
+/// coverage
+fn bucket(hits: u8) -> u8 {
+    match hits {
+        0 => 0,
+        1 => 1,
+        2 => 2,
+        3 => 4,
+        4..=7 => 8,
+        8..=15 => 16,
+        16..=31 => 32,
+        32..=127 => 64,
+        128.. => 128,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_new_coverage() {
+        let coverage = Coverage::new(64).unwrap();
+        assert_eq!(coverage.new_coverage(), None);
+        let map = coverage.map.bytes();
+        map[3].store(1, Ordering::Relaxed);
+        map[7].store(5, Ordering::Relaxed);
+        assert_eq!(coverage.new_coverage(), Some(2));
+        // The map is reset after collecting it
+        assert!(map.iter().all(|hits| hits.load(Ordering::Relaxed) == 0));
+        map[3].store(1, Ordering::Relaxed);
+        map[7].store(6, Ordering::Relaxed);
+        assert_eq!(coverage.new_coverage(), None);
+        // A different bucket of a known edge is new
+        map[3].store(2, Ordering::Relaxed);
+        assert_eq!(coverage.new_coverage(), Some(3));
+        assert_eq!(coverage.edges(), 2);
+        assert_eq!(coverage.env()[1], (MAP_SIZE_ENV_VAR, "64".to_string()));
+    }
+}
@@ -0,0 +1,145 @@
+//! The log output of the broker as feedback. Lines are turned into templates without numbers and
+//! ids, so "Client 3fa2 disconnected" and "Client 9b01 disconnected" are the same template while a
+//! warning about a malformed packet is a new one. Like the coverage, new templates are attributed
+//! to whichever chain collects them first.
+use std::collections::HashSet;
+use std::sync::Mutex;
+
+// Stops brokers which log random data from filling the memory
+const MAX_TEMPLATES: usize = 10_000;
+// Long lines are most likely dumps of packets
+const MAX_TEMPLATE_LEN: usize = 200;
+const PLACEHOLDER: &str = "<*>";
+// Words which are followed by a client id or an address
+const ID_PREFIXES: [&str; 4] = ["client", "Client", "as", "from"];
+
+#[derive(Debug, Default)]
+struct Templates {
+    seen: HashSet<String>,
+    // Templates which no chain has collected yet
+    new: Vec<String>,
+}
+
+#[derive(Debug, Default)]
+pub struct BrokerLog {
+    templates: Mutex<Templates>,
+}
+
+impl BrokerLog {
+    /// Adds a line of the broker output
+    pub fn add(&self, line: &str) {
+        let template = template(line);
+        if template.is_empty() {
+            return;
+        }
+        let mut templates = self.templates.lock().unwrap();
+        if templates.seen.len() < MAX_TEMPLATES && templates.seen.insert(template.clone()) {
+            templates.new.push(template);
+        }
+    }
+
+    /// Takes the templates which were logged for the first time since the last call
+    pub fn new_templates(&self) -> Vec<String> {
+        std::mem::take(&mut self.templates.lock().unwrap().new)
+    }
+
+    pub fn len(&self) -> usize {
+        self.templates.lock().unwrap().seen.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+/// Replaces the words which contain numbers or look like ids, as well as quoted strings
+pub fn template(line: &str) -> String {
+    let mut words = Vec::new();
+    let mut quoted = false;
+    let mut previous = "";
+    for word in line.split_whitespace() {
+        let follows_prefix = ID_PREFIXES.contains(&previous);
+        previous = word;
+        let closes = word.ends_with(['\'', '"']);
+        if quoted {
+            quoted = !closes;
+            continue;
+        }
+        // A quoted string is one placeholder, however many words it has
+        if word.starts_with(['\'', '"']) {
+            words.push(PLACEHOLDER);
+            quoted = word.len() == 1 || !closes;
+            continue;
+        }
+        let id = looks_like_id(word) || (follows_prefix && !looks_like_keyword(word));
+        words.push(match id {
+            true => PLACEHOLDER,
+            false => word,
+        });
+    }
+    let mut template = words.join(" ");
+    if template.len() > MAX_TEMPLATE_LEN {
+        let mut end = MAX_TEMPLATE_LEN;
+        while !template.is_char_boundary(end) {
+            end -= 1;
+        }
+        template.truncate(end);
+    }
+    template
+}
+
+/// Numbers, timestamps, addresses and random client ids
+fn looks_like_id(word: &str) -> bool {
+    let has_digit = word.chars().any(|c| c.is_ascii_digit());
+    // Capitalized words are fine, mixed case after the first letter is not
+    let mut rest = word.chars().skip(1);
+    let mixed_case = word.chars().any(|c| c.is_lowercase()) && rest.any(|c| c.is_uppercase());
+    has_digit || mixed_case
+}
+
+/// Words after an id prefix which aren't ids, as in "New client connected" or "from client"
+fn looks_like_keyword(word: &str) -> bool {
+    let word = word.trim_end_matches(|c: char| c.is_ascii_punctuation());
+    ID_PREFIXES.contains(&word) || word.ends_with("ed") || word.ends_with("ing")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_template() {
+        assert_eq!(
+            template(
+                "1697040000: New client connected from 127.0.0.1:54321 as auto-3F2A (p2, c1, k60)."
+            ),
+            "<*> New client connected from <*> as <*> <*> <*> <*>"
+        );
+        assert_eq!(
+            template("1697040001: Client aBcDeF disconnected due to malformed packet."),
+            "<*> Client <*> disconnected due to malformed packet."
+        );
+        assert_eq!(
+            template("Denied PUBLISH from 'some client' on topic \"a b\""),
+            "Denied PUBLISH from <*> on topic <*>"
+        );
+        assert_eq!(template("   "), "");
+    }
+
+    #[test]
+    fn test_new_templates() {
+        let log = BrokerLog::default();
+        log.add("1: Client abc disconnected.");
+        // Random client ids don't make a new template
+        log.add("2: Client xyz disconnected.");
+        assert_eq!(log.new_templates(), vec!["<*> Client <*> disconnected."]);
+        assert!(log.new_templates().is_empty());
+        log.add("3: Protocol error from client abc.");
+        log.add("4: Client abc disconnected.");
+        assert_eq!(
+            log.new_templates(),
+            vec!["<*> Protocol error from client <*>"]
+        );
+        assert_eq!(log.len(), 2);
+    }
+}
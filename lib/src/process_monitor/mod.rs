@@ -1,23 +1,65 @@
+pub mod coverage;
+pub mod log;
+
+use crate::process_monitor::coverage::Coverage;
+use crate::process_monitor::log::BrokerLog;
+use std::sync::Arc;
 use std::time::Duration;
 use tokio::io::{AsyncBufReadExt, BufReader};
 use tokio::process::Command;
 use tokio::signal;
 use tokio::sync::broadcast::Sender;
 use tokio::sync::oneshot::channel;
-use tokio::time::{sleep, timeout};
+use tokio::time::sleep;
 use tracing::{debug, info};
 
 // TODO: How do the tasks ask if the server has exited? And better yet, how do they get the message back?
-// TODO: Also, how do the tasks know when it has caused new stdout/stderr output?
 // TODO: Allow the user to specify where to write the stdout/stderr of the monitored process. Maybe gzip compress it?
 // TODO: Ask threads what their last packets were and dump it.
+// Separate the coverage count and the log templates from the fingerprint of the responses in keys
+const COVERAGE_KEY: &[u8] = b"coverage";
+const LOG_KEY: &[u8] = b"log";
+
+/// Feedback from the broker process besides its responses
+#[derive(Debug, Default)]
+pub struct Feedback {
+    /// The edge coverage, if the broker is instrumented
+    pub coverage: Option<Coverage>,
+    /// The templates of the lines the broker logged
+    pub log: Option<Arc<BrokerLog>>,
+}
+
+impl Feedback {
+    /// Collects the feedback since the last call. Returns what is new, as a suffix for the key of
+    /// the chain in the queue, or nothing
+    pub fn collect(&self) -> Vec<u8> {
+        let mut key = Vec::new();
+        if let Some(count) = self.coverage.as_ref().and_then(Coverage::new_coverage) {
+            debug!("New coverage, {count} edges and hit counts seen");
+            // The count only grows, so the key is new
+            key.extend(COVERAGE_KEY);
+            key.extend((count as u64).to_be_bytes());
+        }
+        for template in self.log.iter().flat_map(|log| log.new_templates()) {
+            debug!("New log template: {template}");
+            key.extend(LOG_KEY);
+            key.extend(template.as_bytes());
+        }
+        key
+    }
+}
+
 /// Start the broker process and monitor it. If it crashes, we stop our execution.
+/// With a coverage map the broker gets the environment variables to attach to it, with a log every
+/// line of its output is added to it.
 pub async fn start_supervised_process(
     sender: Sender<()>,
     command: String,
+    feedback: &Feedback,
 ) -> color_eyre::Result<()> {
     let mut child = Command::new("/bin/sh")
         .args(["-c", &command])
+        .envs(feedback.coverage.iter().flat_map(Coverage::env))
         .stdout(std::process::Stdio::piped())
         .stderr(std::process::Stdio::piped())
         .spawn()
@@ -36,19 +78,26 @@ pub async fn start_supervised_process(
         info!("Crtl C received, stopping...");
         tx.send(()).expect("Could not send to crtlc_receiver");
     });
+    let log = feedback.log.clone();
     tokio::spawn(async move {
         let mut last_stdout: String = String::new();
         let mut last_stderr: String = String::new();
         loop {
-            if let Ok(Ok(Some(new_stdout))) =
-                timeout(Duration::from_millis(100), stdout_reader.next_line()).await
-            {
-                last_stdout.push_str(new_stdout.as_str());
-            }
-            if let Ok(Ok(Some(new_stderr))) =
-                timeout(Duration::from_millis(100), stderr_reader.next_line()).await
-            {
-                last_stderr.push_str(new_stderr.as_str());
+            // Whichever output has a line first, so a busy stdout doesn't wait for stderr
+            tokio::select! {
+                Ok(Some(new_stdout)) = stdout_reader.next_line() => {
+                    if let Some(log) = &log {
+                        log.add(&new_stdout);
+                    }
+                    last_stdout.push_str(new_stdout.as_str());
+                }
+                Ok(Some(new_stderr)) = stderr_reader.next_line() => {
+                    if let Some(log) = &log {
+                        log.add(&new_stderr);
+                    }
+                    last_stderr.push_str(new_stderr.as_str());
+                }
+                _ = sleep(Duration::from_millis(100)) => {}
             }
             let status = child.try_wait();
             if let Ok(Some(status)) = status {
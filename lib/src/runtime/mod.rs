@@ -1,9 +1,14 @@
+use crate::corpus::Corpus;
+use crate::dictionary::Dictionary;
+use crate::markov::scheduler::Scheduler;
 use crate::markov::StateMachine;
-use crate::network::connect_to_broker;
+use crate::network::{connect_to_broker, Target};
 use crate::packets::PacketQueue;
+use crate::process_monitor::Feedback;
 use crate::SeedAndIterations;
-use rand::{Rng, SeedableRng};
+use rand::SeedableRng;
 use rand_xoshiro::Xoshiro256PlusPlus;
+use std::sync::atomic::{AtomicU64, Ordering};
 use std::sync::Arc;
 use std::time::Duration;
 use tokio::sync::broadcast::Receiver;
@@ -14,13 +19,24 @@ use tokio::time::sleep;
 use tokio::{fs, task};
 use tracing::*;
 
+// Connection attempts of all threads, so the failure rate can be reported instead of every error
+static CONNECTION_ATTEMPTS: AtomicU64 = AtomicU64::new(0);
+static CONNECTION_FAILURES: AtomicU64 = AtomicU64::new(0);
+// How often the failure rate is reported
+const FAILURE_REPORT_INTERVAL: Duration = Duration::from_secs(10);
+
 /// Runs a task that connects to the broker and fuzzes it
+#[allow(clippy::too_many_arguments)]
 pub async fn run_thread(
     seed: u64,
     receiver_clone: Receiver<()>,
-    address: String,
+    target: Target,
     iterations: u64,
     packet_queue: Arc<RwLock<PacketQueue>>,
+    corpus: Arc<Corpus>,
+    dictionary: Arc<Dictionary>,
+    feedback: Arc<Feedback>,
+    scheduler: Arc<Scheduler>,
     it_sender_clone: Sender<u64>,
     timeout: u16,
 ) {
@@ -29,13 +45,13 @@ pub async fn run_thread(
         let mut counter: u64 = 0;
         let mut rng = Xoshiro256PlusPlus::seed_from_u64(seed);
         while counter < iterations {
-            let new_stream = connect_to_broker(&address.clone()).await;
-            if new_stream.is_err() {
-                // Workaround for connections not being closed fast enough. See https://stackoverflow.com/questions/76238841/cant-assign-requested-address-in-request
-                error!(
-                    "Error connecting to broker: {:?}. See recommendations",
-                    new_stream
-                );
+            CONNECTION_ATTEMPTS.fetch_add(1, Ordering::Relaxed);
+            let new_stream = connect_to_broker(&target).await;
+            if let Err(e) = &new_stream {
+                // Usually connections not being closed fast enough. See https://stackoverflow.com/questions/76238841/cant-assign-requested-address-in-request
+                // The failure rate is reported periodically by the iterations tracker
+                CONNECTION_FAILURES.fetch_add(1, Ordering::Relaxed);
+                debug!("Error connecting to broker: {:?}", e);
                 if !receiver_clone.is_empty() {
                     break;
                 }
@@ -44,8 +60,14 @@ pub async fn run_thread(
                 continue;
             }
             let new_tcpstream = new_stream.unwrap();
-            let mut state_machine = StateMachine::new(new_tcpstream, timeout);
-            let mode = rng.gen();
+            let config = scheduler.config();
+            let mut state_machine = StateMachine::new(new_tcpstream, timeout, config.clone())
+                .with_protocol(target.protocol)
+                .with_corpus(corpus.clone())
+                .with_dictionary(dictionary.clone())
+                .with_feedback(feedback.clone())
+                .with_scheduler(scheduler.clone());
+            let mode = config.sample_mode(&mut rng);
             state_machine.execute(mode, &mut rng, &packet_queue).await;
             last_packets = state_machine.previous_packets.clone();
             // We receive a message once the broker is stopped
@@ -53,7 +75,7 @@ pub async fn run_thread(
                 break;
             }
             counter += 1;
-            if counter % 5000 == 0 {
+            if counter.is_multiple_of(5000) {
                 // Display iterations per second
                 let _ = it_sender_clone.send(counter).await;
             }
@@ -86,14 +108,17 @@ pub async fn run_thread(
 }
 
 pub async fn iterations_tracker(threads: usize, mut it_receiver: MpscReceiver<u64>) {
+    // Iterations are only counted after successful connections, so the failures are reported
+    // separately. Otherwise nothing is reported when the broker refuses every connection
+    task::spawn(report_connection_failures());
     let mut last_iterations = 0;
     loop {
         let start = std::time::Instant::now();
         let mut iteration_buffer = vec![0; threads];
-        for i in 1..threads {
+        for iterations in iteration_buffer.iter_mut().skip(1) {
             let value = it_receiver.recv().await;
             match value {
-                Some(v) => iteration_buffer[i] = v,
+                Some(v) => *iterations = v,
                 None => break,
             }
         }
@@ -104,3 +129,25 @@ pub async fn iterations_tracker(threads: usize, mut it_receiver: MpscReceiver<u6
         last_iterations = sum;
     }
 }
+
+async fn report_connection_failures() {
+    let mut interval = tokio::time::interval(FAILURE_REPORT_INTERVAL);
+    let mut last_attempts = 0;
+    let mut last_failures = 0;
+    loop {
+        interval.tick().await;
+        let attempts = CONNECTION_ATTEMPTS.load(Ordering::Relaxed);
+        let failures = CONNECTION_FAILURES.load(Ordering::Relaxed);
+        if failures > last_failures {
+            let failure_rate = (failures - last_failures) as f64
+                / (attempts - last_attempts).max(1) as f64
+                * 100f64;
+            warn!(
+                "{:.1}% of the connection attempts failed. See recommendations",
+                failure_rate
+            );
+        }
+        last_attempts = attempts;
+        last_failures = failures;
+    }
+}
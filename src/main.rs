@@ -1,31 +1,77 @@
 use clap::{Parser, Subcommand};
 use futures::future::join_all;
-use lib::mqtt::test_connection;
-use lib::network::connect_to_broker;
+use lib::corpus::{Corpus, DEFAULT_CORPUS};
+use lib::dictionary::Dictionary;
+use lib::markov::config::MarkovConfig;
+use lib::markov::scheduler::{Scheduler, SCHEDULER_FILE};
+use lib::markov::Protocol;
+use lib::mqtt::signature::Novelty;
+use lib::mqtt::{test_connection, MqttVersion};
+#[cfg(feature = "quic")]
+use lib::network::quic::{QuicConfig, QuicOptions};
+#[cfg(any(feature = "tcp", feature = "tls", feature = "websocket"))]
+use lib::network::tcp::{TcpConfig, TcpOptions};
+#[cfg(feature = "tls")]
+use lib::network::tls::{TlsConfig, TlsOptions};
+use lib::network::{connect_to_broker, Target, Transport};
 use lib::packets::PacketQueue;
-use lib::process_monitor::start_supervised_process;
+use lib::process_monitor::coverage::{Coverage, DEFAULT_MAP_SIZE};
+use lib::process_monitor::{start_supervised_process, Feedback};
 use lib::runtime::{iterations_tracker, run_thread};
 use lib::SeedAndIterations;
 use rand::{thread_rng, Rng};
+use std::path::{Path, PathBuf};
 use std::str::FromStr;
 use std::sync::Arc;
+use std::time::Duration;
 use tokio::sync::mpsc::channel as mpsc_channel;
 use tokio::sync::RwLock;
 use tokio::{fs, task};
-use tracing::{debug, info, trace};
+use tracing::{debug, info, trace, warn};
 
 #[derive(Parser, Debug)]
 #[command(author, version, about)]
 struct Cli {
     #[command(subcommand)]
     subcommand: SubCommands,
+    /// The broker to fuzz. The scheme selects the transport: tcp://, tls://, ws://, wss://, quic://, unix:// or udp:// for MQTT-SN
     #[arg(short, long, default_value = "127.0.0.1:1883")]
-    target: String,
+    target: Transport,
     #[arg(short, long)]
     broker_command: String,
     // TODO: Make the timeout configurable
     #[arg(long, default_value = "200")]
     timeout: u16,
+    /// The MQTT version of the generated packets. MQTT 5 packets get random property lists
+    #[arg(long, value_enum, default_value_t = MqttVersion::V311)]
+    mqtt_version: MqttVersion,
+    /// Additional corpus directories with one hex encoded packet per line in files named after the
+    /// packet type. Packets of a single MQTT version go into the `3.1.1` and `5` subdirectories.
+    /// ./mqtt_corpus is always used if it exists
+    #[arg(long)]
+    corpus: Vec<PathBuf>,
+    /// A TOML file with the probabilities and weights of the markov model. Missing values keep
+    /// their defaults
+    #[arg(long)]
+    markov_config: Option<PathBuf>,
+    /// Dictionaries of tokens for the dictionary mutation, in the AFL format or as TOML with
+    /// `tokens` and `hex` arrays. Tokens from the corpus and the responses are added to them. All
+    /// threads share the learned tokens, so replays may not reproduce a crash
+    #[arg(long)]
+    dictionary: Vec<PathBuf>,
+    /// What makes a response a new behavior. The signature leaves out packet identifiers, topics
+    /// and payloads, so echoed values don't count as new behaviors
+    #[arg(long, value_enum, default_value_t = Novelty::Signature)]
+    novelty: Novelty,
+    #[cfg(any(feature = "tcp", feature = "tls", feature = "websocket"))]
+    #[command(flatten)]
+    tcp: TcpOptions,
+    #[cfg(feature = "tls")]
+    #[command(flatten)]
+    tls: TlsOptions,
+    #[cfg(feature = "quic")]
+    #[command(flatten)]
+    quic: QuicOptions,
 }
 
 #[derive(Subcommand, Debug)]
@@ -34,6 +80,24 @@ enum SubCommands {
     Fuzz {
         #[arg(short, long, default_value_t = 100)]
         threads: u64,
+        /// Shift the weights of the mutations and packet types towards the ones which find new
+        /// behaviors. The learned weights are written to scheduler.toml. Replays use the configured
+        /// weights, so they may not reproduce a crash
+        #[arg(long)]
+        adaptive: bool,
+        /// Start the broker with an AFL compatible shared memory coverage map (`__AFL_SHM_ID`) and
+        /// treat chains which reach new edges as new behaviors. The broker has to be instrumented,
+        /// e.g. by compiling it with afl-cc. Replays don't use the coverage, so they may not
+        /// reproduce a crash
+        #[arg(long)]
+        coverage: bool,
+        /// The size of the coverage map in bytes
+        #[arg(long, default_value_t = DEFAULT_MAP_SIZE)]
+        map_size: usize,
+        /// Treat chains after which the broker logs a new kind of line as new behaviors. Numbers,
+        /// ids and quoted strings are left out when comparing the lines
+        #[arg(long)]
+        log_feedback: bool,
     },
     Replay {
         #[arg(short, long, default_value_t = false)]
@@ -45,11 +109,55 @@ async fn main() -> color_eyre::Result<()> {
     console_subscriber::init();
     color_eyre::install()?;
     let cli = Cli::parse();
-    let packet_queue = Arc::new(RwLock::new(
-        PacketQueue::read_from_file("./packet_pool.toml").await?,
-    ));
+    let mut target = Target::new(cli.target.clone());
+    if target.protocol == Protocol::Mqtt && cli.mqtt_version == MqttVersion::V5 {
+        target = target.with_protocol(Protocol::Mqtt5);
+    }
+    let packet_queue = Arc::new(RwLock::new(match target.protocol {
+        Protocol::Mqtt | Protocol::Mqtt5 => PacketQueue::read_from_file("./packet_pool.toml")
+            .await?
+            .with_novelty(cli.novelty),
+        // The packet pool only contains MQTT chains and the signatures only understand MQTT
+        Protocol::MqttSn => PacketQueue::default().with_novelty(Novelty::Raw),
+    }));
+    let corpus = Arc::new(match target.protocol {
+        Protocol::Mqtt | Protocol::Mqtt5 => read_corpus(&cli.corpus, target.protocol).await?,
+        // The corpus only contains MQTT packets
+        Protocol::MqttSn => Corpus::default(),
+    });
+    let markov_config = Arc::new(match &cli.markov_config {
+        Some(path) => MarkovConfig::read_from_file(path).await?,
+        None => MarkovConfig::default(),
+    });
+    markov_config.validate_for_protocol(target.protocol)?;
+    #[cfg(any(feature = "tcp", feature = "tls", feature = "websocket"))]
+    let target = target.with_tcp(TcpConfig::new(&cli.tcp)?);
+    #[cfg(feature = "tls")]
+    let tls = TlsConfig::new(&cli.tls)?;
+    #[cfg(feature = "quic")]
+    let target = target.with_quic(QuicConfig::new(&cli.quic, &tls)?);
+    #[cfg(feature = "tls")]
+    let target = target.with_tls(tls);
+    let dictionary = Arc::new(read_dictionary(&cli.dictionary, &corpus, target.protocol).await?);
     match &cli.subcommand {
-        SubCommands::Fuzz { threads } => {
+        SubCommands::Fuzz {
+            threads,
+            adaptive,
+            coverage,
+            map_size,
+            log_feedback,
+        } => {
+            let scheduler = Arc::new(match adaptive {
+                true => Scheduler::adaptive(markov_config.clone()),
+                false => Scheduler::fixed(markov_config.clone()),
+            });
+            let feedback = Arc::new(Feedback {
+                coverage: match coverage {
+                    true => Some(Coverage::new(*map_size)?),
+                    false => None,
+                },
+                log: log_feedback.then(Default::default),
+            });
             // The channel used for iteration counting
             let (it_sender, it_receiver) = mpsc_channel::<u64>(*threads as usize);
             // This receiver is necessary to dump the packets once the broker is stopped
@@ -58,11 +166,18 @@ async fn main() -> color_eyre::Result<()> {
             for _ in 0..*threads {
                 subscribers.push(sender.subscribe());
             }
-            start_supervised_process(sender, cli.broker_command).await?;
-            let address = cli.target.clone();
-            let mut stream = connect_to_broker(&cli.target).await?;
-            test_connection(&mut stream).await?;
+            start_supervised_process(sender, cli.broker_command, &feedback).await?;
+            let mut stream = connect_to_broker(&target).await?;
+            match target.protocol {
+                Protocol::Mqtt | Protocol::Mqtt5 => test_connection(&mut stream).await?,
+                Protocol::MqttSn => lib::mqtt_sn::test_connection(&mut stream).await?,
+            }
             info!("Connection established, starting fuzzing!");
+            // The startup and the test connection are no finds of the fuzzer
+            feedback.collect();
+            if let Some(coverage) = &feedback.coverage {
+                info!("The broker covered {} edges at startup", coverage.edges());
+            }
             let mut rng = thread_rng();
             let _ = fs::create_dir("./threads").await;
             let mut task_handles = vec![];
@@ -73,9 +188,13 @@ async fn main() -> color_eyre::Result<()> {
                 task_handles.push(run_thread(
                     seed,
                     receiver_clone,
-                    address.clone(),
+                    target.clone(),
                     u64::MAX,
                     packet_queue.clone(),
+                    corpus.clone(),
+                    dictionary.clone(),
+                    feedback.clone(),
+                    scheduler.clone(),
                     it_sender_clone,
                     cli.timeout,
                 ));
@@ -85,7 +204,22 @@ async fn main() -> color_eyre::Result<()> {
             task::spawn(async move {
                 iterations_tracker(threads, it_receiver).await;
             });
+            if *adaptive {
+                let scheduler = scheduler.clone();
+                task::spawn(async move {
+                    let mut interval = tokio::time::interval(Duration::from_secs(30));
+                    loop {
+                        interval.tick().await;
+                        if let Err(e) = scheduler.write_to_file(SCHEDULER_FILE).await {
+                            warn!("Could not write the scheduler weights: {:?}", e);
+                        }
+                    }
+                });
+            }
             join_all(task_handles).await;
+            if *adaptive {
+                scheduler.write_to_file(SCHEDULER_FILE).await?;
+            }
             let serialized_pkg_pool = toml::to_string(&packet_queue.write().await.clone());
             trace!("Packet Queue: {:?}", serialized_pkg_pool);
         }
@@ -115,9 +249,12 @@ async fn main() -> color_eyre::Result<()> {
             for _ in 0..filtered_files.len() {
                 subscribers.push(sender.subscribe());
             }
-            start_supervised_process(sender, cli.broker_command).await?;
-            let mut stream = connect_to_broker(&cli.target).await?;
-            test_connection(&mut stream).await?;
+            start_supervised_process(sender, cli.broker_command, &Feedback::default()).await?;
+            let mut stream = connect_to_broker(&target).await?;
+            match target.protocol {
+                Protocol::Mqtt | Protocol::Mqtt5 => test_connection(&mut stream).await?,
+                Protocol::MqttSn => lib::mqtt_sn::test_connection(&mut stream).await?,
+            }
             debug!("Connection established");
             debug!("Starting replay with {} seeds", filtered_files.len());
             let mut threads = vec![];
@@ -129,9 +266,13 @@ async fn main() -> color_eyre::Result<()> {
                 threads.push(run_thread(
                     u64::from_str(&seed_and_iterations.seed).unwrap(),
                     receiver_clone,
-                    cli.target.clone(),
+                    target.clone(),
                     u64::from_str(&seed_and_iterations.iterations).unwrap(),
                     packet_queue.clone(),
+                    corpus.clone(),
+                    dictionary.clone(),
+                    Default::default(),
+                    Arc::new(Scheduler::fixed(markov_config.clone())),
                     unused_it_channel.clone(),
                     cli.timeout,
                 ));
@@ -153,3 +294,32 @@ async fn main() -> color_eyre::Result<()> {
     }
     Ok(())
 }
+
+/// Reads the shipped corpus, if it exists, and the user supplied ones. Only the packets of the MQTT
+/// version of the protocol are read
+async fn read_corpus(directories: &[PathBuf], protocol: Protocol) -> color_eyre::Result<Corpus> {
+    let mut corpus = Corpus::default();
+    if Path::new(DEFAULT_CORPUS).is_dir() {
+        corpus.extend(Corpus::read_from_dir(DEFAULT_CORPUS, protocol).await?);
+    }
+    for directory in directories {
+        corpus.extend(Corpus::read_from_dir(directory, protocol).await?);
+    }
+    info!("Loaded {} packets from the corpus", corpus.len());
+    Ok(corpus)
+}
+
+/// Reads the dictionaries and adds the tokens of the corpus
+async fn read_dictionary(
+    files: &[PathBuf],
+    corpus: &Corpus,
+    protocol: Protocol,
+) -> color_eyre::Result<Dictionary> {
+    let dictionary = Dictionary::default();
+    for file in files {
+        dictionary.read_from_file(file).await?;
+    }
+    dictionary.extract_from_corpus(corpus, protocol.mqtt_version());
+    debug!("The dictionary starts with {} tokens", dictionary.len());
+    Ok(dictionary)
+}